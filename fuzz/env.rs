@@ -0,0 +1,42 @@
+// The variables the fuzz targets expand against. The crate's tests include this
+// file too, to replay `fuzz/regressions` against the same variables.
+
+/// Variables to expand, including one that points at another and one whose
+/// value is not a valid name.
+fn fuzz_env() -> FuzzEnv {
+    FuzzEnv(
+        FakeEnv::empty()
+            .with("FOO", "foo")
+            .with("EMPTY", "")
+            .with("REF", "FOO")
+            .with("BAD_REF", "}{"),
+    )
+}
+
+/// A [`FakeEnv`] that panics wherever `std::env::set_var` would, so that
+/// assignments the process environment cannot take are caught too.
+struct FuzzEnv(FakeEnv);
+
+impl Enviroment for FuzzEnv {
+    fn get<K: AsRef<std::ffi::OsStr>>(&self, key: K) -> Option<std::ffi::OsString> {
+        self.0.get(key)
+    }
+
+    fn set<K: AsRef<std::ffi::OsStr>, V: AsRef<std::ffi::OsStr>>(&mut self, key: K, value: V) {
+        let (key, value) = (key.as_ref(), value.as_ref());
+        assert!(!key.is_empty(), "set_var with an empty key");
+        assert!(
+            !key.as_encoded_bytes().contains(&b'='),
+            "set_var with `=` in the key {key:?}"
+        );
+        assert!(
+            !key.as_encoded_bytes().contains(&0),
+            "set_var with NUL in the key {key:?}"
+        );
+        assert!(
+            !value.as_encoded_bytes().contains(&0),
+            "set_var with NUL in the value {value:?}"
+        );
+        self.0.set(key, value);
+    }
+}
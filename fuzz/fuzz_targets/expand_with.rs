@@ -0,0 +1,13 @@
+#![no_main]
+
+use expandvars::env::{Enviroment, FakeEnv};
+use libfuzzer_sys::fuzz_target;
+
+include!("../env.rs");
+
+fuzz_target!(|input: &[u8]| {
+    let _ = expandvars::expand_bytes_with(&mut fuzz_env(), input);
+    if let Ok(input) = std::str::from_utf8(input) {
+        let _ = expandvars::expand_with(&mut fuzz_env(), input);
+    }
+});
@@ -0,0 +1,15 @@
+#![no_main]
+
+use expandvars::{
+    env::{Enviroment, FakeEnv},
+    options::Options,
+};
+use libfuzzer_sys::fuzz_target;
+
+include!("../env.rs");
+
+fuzz_target!(|input: (char, bool, &str)| {
+    let (sigil, nounset, input) = input;
+    let options = Options::new().sigil(sigil).nounset(nounset);
+    let _ = options.expand_with(&mut fuzz_env(), input);
+});
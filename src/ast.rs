@@ -0,0 +1,156 @@
+//! The syntax tree of a template, as returned by [`parse`](crate::parse).
+//!
+//! ```
+//! use expandvars::ast::{NodeKind, Operator};
+//!
+//! let nodes = expandvars::parse("Hi ${NAME:-you}").unwrap();
+//! assert_eq!(nodes[0].kind, NodeKind::Text("Hi "));
+//!
+//! let NodeKind::Param(param) = &nodes[1].kind else {
+//!     panic!("not a parameter");
+//! };
+//! assert_eq!(param.name, "NAME");
+//! assert_eq!((nodes[1].span.start, nodes[1].span.end), (3, 15));
+//!
+//! let Some(Operator::Default { colon: true, word }) = &param.operator else {
+//!     panic!("not a default");
+//! };
+//! assert_eq!(word[0].kind, NodeKind::Text("you"));
+//! ```
+
+use crate::{
+    error::{Error, Span},
+    token::{self, Token},
+};
+
+/// A piece of a template, along with where it is.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Node<'a> {
+    pub kind: NodeKind<'a>,
+    /// The piece as written, including any sigil, braces or backslashes.
+    pub span: Span,
+}
+
+/// What a [`Node`] is.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum NodeKind<'a> {
+    /// Literal text.
+    Text(&'a str),
+    /// Backslashes before the sigil, such as `\$`, holding the text they stand
+    /// for.
+    Escape(&'a str),
+    /// A literal character that is not part of a substitution, such as a lone
+    /// `$` or `}`, or a brace in a balanced pair inside an operator's word.
+    Char(char),
+    /// `$$` or `${$}`, the ID of the current process.
+    Pid,
+    /// A variable reference, such as `$FOO` or `${FOO:-default}`.
+    Param(Param<'a>),
+}
+
+/// A variable reference.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Param<'a> {
+    /// The name of the variable, without the sigil or braces.
+    pub name: &'a str,
+    /// Whether the reference is indirect, as in `${!FOO}`, so that the value of
+    /// `name` is itself the name of the variable to expand.
+    pub indirect: bool,
+    /// The operator applied to the value, if any.
+    pub operator: Option<Operator<'a>>,
+}
+
+/// An operator applied to a variable inside `${...}`.
+///
+/// `colon` is whether the colon form of the operator was used, as in `:-` rather
+/// than `-`, in which case a set but empty variable is treated as unset.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Operator<'a> {
+    /// `${FOO:-word}`: expands to `word` if `FOO` is unset.
+    Default { colon: bool, word: Vec<Node<'a>> },
+    /// `${FOO:=word}`: like `Default`, and also assigns `word` to `FOO`.
+    Assign { colon: bool, word: Vec<Node<'a>> },
+    /// `${FOO:+word}`: expands to `word` if `FOO` is set, and to nothing otherwise.
+    Alternate { colon: bool, word: Vec<Node<'a>> },
+    /// `${FOO:?message}`: fails with `message` if `FOO` is unset.
+    Error { colon: bool, message: Vec<Node<'a>> },
+    /// `${FOO:offset}` or `${FOO:offset:length}`. The operands are kept as
+    /// written, and are only evaluated on expansion.
+    Substring {
+        offset: &'a str,
+        length: Option<&'a str>,
+    },
+}
+
+/// Converts tokens of `input`, the template they were parsed from.
+struct Builder<'a> {
+    input: &'a [u8],
+}
+
+impl<'a> Builder<'a> {
+    fn to_str(&self, bytes: &'a [u8]) -> Result<&'a str, Error> {
+        std::str::from_utf8(bytes).map_err(|_| Error::not_unicode(self.input, bytes))
+    }
+
+    fn node(&self, tok: &Token<'a>) -> Result<Node<'a>, Error> {
+        let (kind, source) = match tok {
+            Token::Const(s) => (NodeKind::Text(self.to_str(s)?), *s),
+            Token::Escape(s) => (NodeKind::Escape(self.to_str(token::unescape(s))?), *s),
+            Token::Char(s, c) => (NodeKind::Char(*c), *s),
+            Token::Pid(s) => (NodeKind::Pid, *s),
+            Token::Param(param) => (NodeKind::Param(self.param(param)?), param.source),
+        };
+        Ok(Node {
+            kind,
+            span: Span::of(self.input, source),
+        })
+    }
+
+    fn nodes(&self, tokens: &[Token<'a>]) -> Result<Vec<Node<'a>>, Error> {
+        tokens.iter().map(|tok| self.node(tok)).collect()
+    }
+
+    fn param(&self, param: &token::Param<'a>) -> Result<Param<'a>, Error> {
+        let operator = match &param.operator {
+            None => None,
+            Some(token::Operator::Default(colon, word)) => Some(Operator::Default {
+                colon: *colon,
+                word: self.nodes(word)?,
+            }),
+            Some(token::Operator::Assign(colon, word)) => Some(Operator::Assign {
+                colon: *colon,
+                word: self.nodes(word)?,
+            }),
+            Some(token::Operator::Alternate(colon, word)) => Some(Operator::Alternate {
+                colon: *colon,
+                word: self.nodes(word)?,
+            }),
+            Some(token::Operator::Error(colon, message)) => Some(Operator::Error {
+                colon: *colon,
+                message: self.nodes(message)?,
+            }),
+            Some(token::Operator::Substring(offset, length)) => {
+                Some(Operator::Substring {
+                    offset: self.to_str(offset)?,
+                    length: length.map(|length| self.to_str(length)).transpose()?,
+                })
+            }
+        };
+        Ok(Param {
+            name: self.to_str(param.name)?,
+            indirect: param.indirect,
+            operator,
+        })
+    }
+}
+
+pub(crate) fn from_tokens<'a>(
+    input: &'a [u8],
+    tokens: &[Token<'a>],
+) -> Result<Vec<Node<'a>>, Error> {
+    Builder { input }.nodes(tokens)
+}
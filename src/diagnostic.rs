@@ -0,0 +1,394 @@
+//! Renders errors the way a compiler would, quoting the line of the template
+//! they come from and pointing at the problem. Needs the `diagnostics` feature.
+//!
+//! ```
+//! use expandvars::diagnostic;
+//!
+//! let template = "Hi ${NAME";
+//! let err = expandvars::expand(template).unwrap_err();
+//! assert_eq!(
+//!     diagnostic::render(&err, template),
+//!     "\
+//! error: missing '}'
+//!   |
+//! 1 | Hi ${NAME
+//!   |    ^^ this `${` is never closed
+//!   |
+//!   = help: add a `}` after `NAME`",
+//! );
+//! ```
+
+use std::ops::Range;
+
+use annotate_snippets::{Level, Renderer, Snippet};
+
+use crate::{error::Error, parser::is_variable_name};
+
+/// Renders `error` without colors. `source` must be the template the error
+/// came from.
+pub fn render(error: &Error, source: &str) -> String {
+    render_with(&Renderer::plain(), error, source)
+}
+
+/// Like [`render`], with ANSI colors for a terminal.
+pub fn render_styled(error: &Error, source: &str) -> String {
+    render_with(&Renderer::styled(), error, source)
+}
+
+fn render_with(renderer: &Renderer, error: &Error, source: &str) -> String {
+    let title = title(error);
+    let annotation = annotate(error, source);
+
+    let mut message = Level::Error.title(&title);
+    if let Some(annotation) = &annotation {
+        let mut snippet = Snippet::source(source).fold(true).annotation(
+            Level::Error
+                .span(annotation.range.clone())
+                .label(&annotation.label),
+        );
+        if let Some((range, label)) = &annotation.also {
+            snippet = snippet.annotation(Level::Help.span(range.clone()).label(label));
+        }
+        message = message.snippet(snippet);
+        if let Some(help) = &annotation.help {
+            message = message.footer(Level::Help.title(help));
+        }
+    }
+    // Bound so that the rendered message is dropped before what it borrows.
+    let rendered = renderer.render(message).to_string();
+    rendered
+}
+
+/// Parse errors quote the whole template in their message, which the snippet
+/// already shows.
+fn title(error: &Error) -> String {
+    match error {
+        Error::Parse { .. } => "unexpected input".into(),
+        Error::MissingEscapedChar { .. } => "missing escaped character".into(),
+        Error::MissingClosingBrace { .. } => "missing '}'".into(),
+        Error::BadSubstitution { .. } => "bad substitution".into(),
+        Error::TooDeep { max_depth, .. } => format!("nesting deeper than {max_depth}"),
+        error => error.to_string(),
+    }
+}
+
+struct Annotation {
+    range: Range<usize>,
+    label: String,
+    help: Option<String>,
+    /// Another place to point at, such as where something is missing.
+    also: Option<(Range<usize>, String)>,
+}
+
+fn annotate(error: &Error, source: &str) -> Option<Annotation> {
+    let position = error.position()?;
+    let range = position.start..position.end;
+    let text = source.get(range.clone())?;
+
+    let annotation = match error {
+        Error::Parse { .. } => Annotation {
+            range,
+            label: "could not parse from here".into(),
+            help: None,
+            also: None,
+        },
+        Error::MissingEscapedChar { .. } => Annotation {
+            range,
+            label: "nothing to escape".into(),
+            help: Some("write `\\\\` for a literal backslash".into()),
+            also: None,
+        },
+        Error::MissingClosingBrace { .. } => {
+            let opener = opener(text)?;
+            let body = &text[opener.len()..];
+            let name = name(body);
+            let label = format!("this `{opener}` is never closed");
+            let range = range.start..range.start + opener.len();
+            // With an operator after the name, closing right after the name
+            // would change what the reference means.
+            if body.trim_start_matches('!').len() > name.len() {
+                let last = text.chars().next_back()?;
+                let end = position.end;
+                return Some(Annotation {
+                    range,
+                    label,
+                    help: None,
+                    also: Some((
+                        end - last.len_utf8()..end,
+                        "add a `}` after this".into(),
+                    )),
+                });
+            }
+            Annotation {
+                range,
+                label,
+                help: Some(if name.is_empty() {
+                    "add a `}` to close it".into()
+                } else {
+                    format!("add a `}}` after `{name}`")
+                }),
+                also: None,
+            }
+        }
+        Error::BadSubstitution { .. } => {
+            let opener = opener(text)?;
+            let name = name(&text[opener.len()..]);
+            let sigil = &opener[..opener.len() - 1];
+            let rest = &source[range.end..];
+            let help = match text[opener.len() + name.len()..].chars().next() {
+                _ if name.is_empty() => Some(format!(
+                    "put a variable name between the braces, as in `{opener}NAME}}`"
+                )),
+                Some(':') => {
+                    Some("a substring takes at most an offset and a length".into())
+                }
+                Some(c) if c.is_ascii_punctuation() && rest.contains('}') => {
+                    let word = rest.split('}').next().unwrap_or_default();
+                    Some(format!("did you mean `{sigil}{{{name}:-{word}}}`?"))
+                }
+                _ => None,
+            };
+            Annotation {
+                range,
+                label: "not a valid substitution".into(),
+                help,
+                also: None,
+            }
+        }
+        Error::TooDeep { .. } => Annotation {
+            range,
+            label: "nested too deeply".into(),
+            help: Some("flatten the template, or raise `Options::max_depth`".into()),
+            also: None,
+        },
+        Error::ParameterNullOrNotSet { .. } => Annotation {
+            range,
+            label: "null or not set".into(),
+            help: None,
+            also: None,
+        },
+        Error::UnsetVariable { name, .. } => {
+            let sigil = source[..range.start]
+                .trim_end_matches(['{', '!'])
+                .chars()
+                .last()
+                .unwrap_or('$');
+            Annotation {
+                range,
+                label: "not set".into(),
+                help: Some(format!(
+                    "set `{name}`, or give it a default as in `{sigil}{{{name}:-default}}`"
+                )),
+                also: None,
+            }
+        }
+        Error::NotUnicode { .. } => Annotation {
+            range,
+            label: "not valid UTF-8".into(),
+            help: Some("set `Options::lossy` to replace what is not valid UTF-8".into()),
+            also: None,
+        },
+        Error::NulInValue { .. } => Annotation {
+            range,
+            label: "cannot be assigned".into(),
+            help: Some("environment variables cannot hold NUL bytes".into()),
+            also: None,
+        },
+        Error::DeniedVariable { .. } => Annotation {
+            range,
+            label: "not allowed".into(),
+            help: None,
+            also: None,
+        },
+        Error::NegativeSubStringExpression { .. } => Annotation {
+            range,
+            label: "negative length".into(),
+            help: Some("a substring length must be zero or more".into()),
+            also: None,
+        },
+        Error::OperandExpected { .. } => Annotation {
+            range,
+            label: "not a number".into(),
+            help: Some("substring offsets and lengths must be integers".into()),
+            also: None,
+        },
+        _ => return None,
+    };
+    Some(annotation)
+}
+
+/// The sigil and opening brace that a span inside `${` starts with.
+fn opener(text: &str) -> Option<&str> {
+    text.find('{').map(|n| &text[..=n])
+}
+
+fn name(text: &str) -> &str {
+    let text = text.strip_prefix('!').unwrap_or(text);
+    let end = text
+        .bytes()
+        .position(|c| !is_variable_name(c))
+        .unwrap_or(text.len());
+    &text[..end]
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::{env::FakeEnv, options::Options};
+
+    fn diagnose(input: &str) -> String {
+        let mut env = FakeEnv::empty().with("FOO", "foo");
+        let err = Options::new()
+            .nounset(true)
+            .expand_with(&mut env, input)
+            .unwrap_err();
+        render(&err, input)
+    }
+
+    #[test]
+    fn test_unterminated_brace() {
+        assert_eq!(
+            diagnose("first line\nHello ${NAME"),
+            "\
+error: missing '}'
+  |
+2 | Hello ${NAME
+  |       ^^ this `${` is never closed
+  |
+  = help: add a `}` after `NAME`"
+        );
+
+        assert!(diagnose("${!FOO").ends_with("help: add a `}` after `FOO`"));
+
+        assert_eq!(
+            diagnose("${FOO:-${"),
+            "\
+error: missing '}'
+  |
+1 | ${FOO:-${
+  |        ^^ this `${` is never closed
+  |
+  = help: add a `}` to close it"
+        );
+
+        assert_eq!(
+            diagnose("${FOO:-bar"),
+            "\
+error: missing '}'
+  |
+1 | ${FOO:-bar
+  | ^^       - help: add a `}` after this
+  | |
+  | this `${` is never closed
+  |"
+        );
+
+        assert_eq!(
+            diagnose("a {${FOO:-{x} b"),
+            "\
+error: missing '}'
+  |
+1 | a {${FOO:-{x} b
+  |    ^^         - help: add a `}` after this
+  |    |
+  |    this `${` is never closed
+  |"
+        );
+    }
+
+    #[test]
+    fn test_bad_operator() {
+        assert_eq!(
+            diagnose("${FOO*x}"),
+            "\
+error: bad substitution
+  |
+1 | ${FOO*x}
+  | ^^^^^^ not a valid substitution
+  |
+  = help: did you mean `${FOO:-x}`?"
+        );
+
+        assert_eq!(
+            diagnose("${FOO:1:2:3}"),
+            "\
+error: bad substitution
+  |
+1 | ${FOO:1:2:3}
+  | ^^^^^^^^^^ not a valid substitution
+  |
+  = help: a substring takes at most an offset and a length"
+        );
+
+        assert_eq!(
+            diagnose("${FOO bar}"),
+            "\
+error: bad substitution
+  |
+1 | ${FOO bar}
+  | ^^^^^^ not a valid substitution
+  |"
+        );
+
+        assert_eq!(
+            diagnose("${}"),
+            "\
+error: bad substitution
+  |
+1 | ${}
+  | ^^^ not a valid substitution
+  |
+  = help: put a variable name between the braces, as in `${NAME}`"
+        );
+    }
+
+    #[test]
+    fn test_expansion_errors() {
+        assert_eq!(
+            diagnose("${FOO:0:x}"),
+            "\
+error: FOO: operand expected (error token is 'x')
+  |
+1 | ${FOO:0:x}
+  |         ^ not a number
+  |
+  = help: substring offsets and lengths must be integers"
+        );
+
+        assert_eq!(
+            diagnose("say $BAR"),
+            "\
+error: BAR: unbound variable
+  |
+1 | say $BAR
+  |      ^^^ not set
+  |
+  = help: set `BAR`, or give it a default as in `${BAR:-default}`"
+        );
+
+        #[cfg(unix)]
+        {
+            use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+            let mut env = FakeEnv::empty().with("DIR", OsStr::from_bytes(b"caf\xe9"));
+            let err = crate::expand_with(&mut env, "cd $DIR").unwrap_err();
+            assert_eq!(
+                render(&err, "cd $DIR"),
+                "\
+error: DIR: not valid UTF-8
+  |
+1 | cd $DIR
+  |     ^^^ not valid UTF-8
+  |
+  = help: set `Options::lossy` to replace what is not valid UTF-8"
+            );
+        }
+
+        let err = crate::error::Error::from(std::fmt::Error);
+        assert_eq!(
+            render(&err, "$FOO"),
+            "error: an error occurred when formatting an argument"
+        );
+    }
+}
@@ -39,7 +39,7 @@ impl Default for FakeEnv {
     }
 }
 
-impl<'a> Enviroment for FakeEnv {
+impl Enviroment for FakeEnv {
     fn get<K: AsRef<OsStr>>(&self, key: K) -> Option<OsString> {
         self.0.get(key.as_ref()).cloned()
     }
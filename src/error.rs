@@ -1,9 +1,198 @@
-use std::{env::VarError, string::FromUtf8Error};
+use std::{env::VarError, fmt, io, ops::Deref, str::Utf8Error, string::FromUtf8Error};
 
-#[derive(Debug, PartialEq, Eq)]
+use nom::Offset;
+
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
 pub enum Error {
+    #[error(transparent)]
     FromUtf8Error(FromUtf8Error),
+
+    #[error(transparent)]
+    Utf8Error(Utf8Error),
+
+    #[error(transparent)]
     VarError(VarError),
+
+    #[error(transparent)]
+    Fmt(fmt::Error),
+
+    #[error(transparent)]
+    Io(IoError),
+
+    /// Input the parser could not make sense of, spanning from where it stopped
+    /// to the end of the template.
+    #[error("{input}: unexpected input at {position}")]
+    Parse { input: String, position: Span },
+
+    /// A backslash at the very end of the template, with nothing to escape.
+    #[error("{input}: missing escaped character")]
+    MissingEscapedChar { input: String, position: Span },
+
+    /// A `${` that is never closed, spanning from the `${` to the end.
+    #[error("{input}: missing '}}'")]
+    MissingClosingBrace { input: String, position: Span },
+
+    /// A malformed `${...}`, spanning from the `${` to the first character that
+    /// does not belong.
+    #[error("{input}: bad substitution")]
+    BadSubstitution { input: String, position: Span },
+
+    /// Substitutions or braces nested more than
+    /// [`max_depth`](crate::options::Options::max_depth) deep, spanning the
+    /// opening of the one that goes too far.
+    #[error("{input}: nesting deeper than {max_depth}")]
+    TooDeep {
+        input: String,
+        max_depth: usize,
+        position: Span,
+    },
+
+    /// Expanding would write more than
+    /// [`max_output`](crate::options::Options::max_output) bytes.
+    #[error("output exceeds {max_output} bytes")]
+    OutputTooLarge { max_output: usize },
+
+    /// `${FOO:?message}` with `FOO` unset, spanning the name.
+    #[error("{name}: {message}")]
+    ParameterNullOrNotSet {
+        name: String,
+        message: String,
+        position: Span,
+    },
+
+    /// A negative substring length, spanning the length as written.
+    #[error("{name}: {expression}: substring expression < 0")]
+    NegativeSubStringExpression {
+        name: String,
+        expression: String,
+        position: Span,
+    },
+
+    /// An unset variable in [nounset](crate::options::Options::nounset) mode,
+    /// spanning the name.
+    #[error("{name}: unbound variable")]
+    UnsetVariable { name: String, position: Span },
+
+    /// A variable whose value, or a name given indirectly, is not valid UTF-8
+    /// where text is needed, spanning the name. Also raised for text that is
+    /// not valid UTF-8 in the syntax tree of a template, spanning the text.
+    #[error("{name}: not valid UTF-8")]
+    NotUnicode { name: String, position: Span },
+
+    /// `${FOO:=default}` with a default containing a NUL byte, which cannot be
+    /// assigned to an environment variable, spanning the name.
+    #[error("{name}: cannot assign a value containing a NUL byte")]
+    NulInValue { name: String, position: Span },
+
+    /// A variable the [filter](crate::filter::Filter) denies, spanning the name.
+    #[error("{name}: not allowed")]
+    DeniedVariable { name: String, position: Span },
+
+    /// A substring offset or length that is not a number, spanning the operand.
+    #[error("{name}: operand expected (error token is '{token}')")]
+    OperandExpected {
+        name: String,
+        token: String,
+        position: Span,
+    },
+}
+
+impl Error {
+    /// For `part` of `input` turning out not to be valid UTF-8.
+    pub(crate) fn not_unicode(input: &[u8], part: &[u8]) -> Self {
+        Self::NotUnicode {
+            name: String::from_utf8_lossy(part).into_owned(),
+            position: Span::of(input, part),
+        }
+    }
+
+    /// Where in the template the error occurred, for errors that have to do with
+    /// a particular part of it.
+    pub fn position(&self) -> Option<Span> {
+        match self {
+            Self::Parse { position, .. }
+            | Self::MissingEscapedChar { position, .. }
+            | Self::MissingClosingBrace { position, .. }
+            | Self::BadSubstitution { position, .. }
+            | Self::TooDeep { position, .. }
+            | Self::ParameterNullOrNotSet { position, .. }
+            | Self::NegativeSubStringExpression { position, .. }
+            | Self::UnsetVariable { position, .. }
+            | Self::NotUnicode { position, .. }
+            | Self::NulInValue { position, .. }
+            | Self::DeniedVariable { position, .. }
+            | Self::OperandExpected { position, .. } => Some(*position),
+            Self::FromUtf8Error(_)
+            | Self::Utf8Error(_)
+            | Self::VarError(_)
+            | Self::Fmt(_)
+            | Self::Io(_)
+            | Self::OutputTooLarge { .. } => None,
+        }
+    }
+}
+
+/// An [`io::Error`] that can be compared, by its kind and message.
+#[derive(Debug, thiserror::Error)]
+#[error(transparent)]
+pub struct IoError(io::Error);
+
+impl IoError {
+    pub fn into_inner(self) -> io::Error {
+        self.0
+    }
+}
+
+impl Deref for IoError {
+    type Target = io::Error;
+
+    fn deref(&self) -> &io::Error {
+        &self.0
+    }
+}
+
+impl PartialEq for IoError {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.kind() == other.0.kind() && self.0.to_string() == other.0.to_string()
+    }
+}
+
+impl Eq for IoError {}
+
+/// A range of bytes in a template, along with the line and column it starts
+/// at. Lines and columns count from 1, and columns count characters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Span {
+    /// The span of `part`, which must be a slice of `input`.
+    pub(crate) fn of(input: &[u8], part: &[u8]) -> Self {
+        let start = input.offset(part);
+        let line_start = input[..start]
+            .iter()
+            .rposition(|&c| c == b'\n')
+            .map_or(0, |n| n + 1);
+        Self {
+            start,
+            end: start + part.len(),
+            line: input[..start].iter().filter(|&&c| c == b'\n').count() + 1,
+            column: String::from_utf8_lossy(&input[line_start..start])
+                .chars()
+                .count()
+                + 1,
+        }
+    }
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}, column {}", self.line, self.column)
+    }
 }
 
 impl From<VarError> for Error {
@@ -12,10 +201,28 @@ impl From<VarError> for Error {
     }
 }
 
+impl From<Utf8Error> for Error {
+    fn from(v: Utf8Error) -> Self {
+        Self::Utf8Error(v)
+    }
+}
+
+impl From<fmt::Error> for Error {
+    fn from(v: fmt::Error) -> Self {
+        Self::Fmt(v)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(v: io::Error) -> Self {
+        Self::Io(IoError(v))
+    }
+}
+
 impl From<FromUtf8Error> for Error {
     fn from(v: FromUtf8Error) -> Self {
         Self::FromUtf8Error(v)
     }
 }
 
-pub type Result = std::result::Result<String, Error>;
+pub type Result<T = String> = std::result::Result<T, Error>;
@@ -0,0 +1,143 @@
+//! Restricts which variables a template may expand, for templates that come
+//! from someone who should not see everything in the environment.
+//!
+//! ```
+//! use expandvars::{
+//!     env::FakeEnv,
+//!     filter::{Filter, OnDenied, Pattern},
+//!     options::Options,
+//! };
+//!
+//! let mut env = FakeEnv::empty()
+//!     .with("APP_NAME", "demo")
+//!     .with("AWS_SECRET_ACCESS_KEY", "hunter2");
+//!
+//! let filter = Filter::new()
+//!     .deny(Pattern::glob("*SECRET*"))
+//!     .on_denied(OnDenied::Literal);
+//! let res = Options::new()
+//!     .filter(filter)
+//!     .expand_with(&mut env, "$APP_NAME: ${AWS_SECRET_ACCESS_KEY}");
+//! assert_eq!(res.unwrap(), "demo: ${AWS_SECRET_ACCESS_KEY}");
+//! ```
+
+/// Which variable names a [`Pattern`] matches.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Pattern {
+    /// Exactly this name.
+    Name(String),
+    /// Any name that starts with this.
+    Prefix(String),
+    /// Any name that matches this glob, where `*` stands for any run of
+    /// characters and `?` for any single one.
+    Glob(String),
+}
+
+impl Pattern {
+    pub fn name(name: impl Into<String>) -> Self {
+        Self::Name(name.into())
+    }
+
+    pub fn prefix(prefix: impl Into<String>) -> Self {
+        Self::Prefix(prefix.into())
+    }
+
+    pub fn glob(glob: impl Into<String>) -> Self {
+        Self::Glob(glob.into())
+    }
+
+    pub fn matches(&self, name: &str) -> bool {
+        match self {
+            Self::Name(n) => name == n,
+            Self::Prefix(prefix) => name.starts_with(prefix.as_str()),
+            Self::Glob(glob) => {
+                let glob: Vec<char> = glob.chars().collect();
+                let name: Vec<char> = name.chars().collect();
+                glob_matches(&glob, &name)
+            }
+        }
+    }
+}
+
+/// Matches with backtracking to the last `*` only, which is enough since a
+/// later `*` can always absorb what an earlier one would have.
+fn glob_matches(glob: &[char], name: &[char]) -> bool {
+    let (mut g, mut n) = (0, 0);
+    let mut star = None;
+    while n < name.len() {
+        match glob.get(g) {
+            Some('*') => {
+                star = Some((g, n));
+                g += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                g += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((star_g, star_n)) => {
+                    star = Some((star_g, star_n + 1));
+                    g = star_g + 1;
+                    n = star_n + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    glob[g..].iter().all(|&c| c == '*')
+}
+
+/// What a reference to a denied variable expands to.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OnDenied {
+    /// The variable reads as unset, so that `${SECRET:-default}` still expands
+    /// to `default`. Assigning to it with `${SECRET:=value}` is skipped.
+    #[default]
+    Empty,
+    /// The whole reference is left as written, such as `${SECRET:-default}`.
+    Literal,
+    /// Expansion fails with [`DeniedVariable`](crate::error::Error::DeniedVariable).
+    Error,
+}
+
+/// Allow and deny lists of variables. A variable may be expanded if it matches
+/// none of the denied patterns, and either there are no allowed patterns or it
+/// matches one of them.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Filter {
+    allow: Vec<Pattern>,
+    deny: Vec<Pattern>,
+    on_denied: OnDenied,
+}
+
+impl Filter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn allow(mut self, pattern: Pattern) -> Self {
+        self.allow.push(pattern);
+        self
+    }
+
+    pub fn deny(mut self, pattern: Pattern) -> Self {
+        self.deny.push(pattern);
+        self
+    }
+
+    /// Sets what denied variables expand to, [`OnDenied::Empty`] by default.
+    pub fn on_denied(mut self, on_denied: OnDenied) -> Self {
+        self.on_denied = on_denied;
+        self
+    }
+
+    pub fn allows(&self, name: &str) -> bool {
+        (self.allow.is_empty() || self.allow.iter().any(|p| p.matches(name)))
+            && !self.deny.iter().any(|p| p.matches(name))
+    }
+
+    pub(crate) fn policy(&self) -> OnDenied {
+        self.on_denied
+    }
+}
@@ -1,13 +1,39 @@
+use std::{
+    borrow::Cow,
+    ffi::{OsStr, OsString},
+    fmt,
+    io::{self, Read},
+    path::Path,
+};
+
 use env::{Enviroment, ProcessEnv};
+use options::Options;
 
+pub mod ast;
+#[cfg(feature = "diagnostics")]
+pub mod diagnostic;
 pub mod env;
 pub mod error;
+pub mod filter;
+pub mod options;
 mod parser;
+pub mod template;
 mod token;
+pub mod variables;
 
 #[cfg(test)]
 mod tests;
 
+/// Parses `input` into its syntax tree without expanding it.
+pub fn parse(input: &str) -> error::Result<Vec<ast::Node<'_>>> {
+    Options::default().parse(input)
+}
+
+/// Lists the variables `input` refers to without expanding it.
+pub fn variables(input: &str) -> error::Result<Vec<variables::Variable<'_>>> {
+    Options::default().variables(input)
+}
+
 pub fn expand(input: &str) -> error::Result {
     expand_with(&mut ProcessEnv, input)
 }
@@ -16,10 +42,78 @@ pub fn expand_with<E>(env: &mut E, input: &str) -> error::Result
 where
     E: Enviroment,
 {
-    let tokens = parser::parse(input.as_bytes()).unwrap().1?;
-    let mut res = String::new();
-    for tok in tokens {
-        res.push_str(&tok.expand_with(env)?);
-    }
-    Ok(res)
+    Options::default().expand_with(env, input)
+}
+
+pub fn expand_cow(input: &str) -> error::Result<Cow<'_, str>> {
+    expand_cow_with(&mut ProcessEnv, input)
+}
+
+pub fn expand_cow_with<'a, E>(env: &mut E, input: &'a str) -> error::Result<Cow<'a, str>>
+where
+    E: Enviroment,
+{
+    Options::default().expand_cow_with(env, input)
+}
+
+pub fn expand_bytes(input: &[u8]) -> error::Result<Vec<u8>> {
+    expand_bytes_with(&mut ProcessEnv, input)
+}
+
+pub fn expand_bytes_with<E>(env: &mut E, input: &[u8]) -> error::Result<Vec<u8>>
+where
+    E: Enviroment,
+{
+    Options::default().expand_bytes_with(env, input)
+}
+
+pub fn expand_os(input: &OsStr) -> error::Result<OsString> {
+    expand_os_with(&mut ProcessEnv, input)
+}
+
+pub fn expand_os_with<E>(env: &mut E, input: &OsStr) -> error::Result<OsString>
+where
+    E: Enviroment,
+{
+    Options::default().expand_os_with(env, input)
+}
+
+pub fn expand_into<W, E>(out: &mut W, env: &mut E, input: &str) -> error::Result<()>
+where
+    W: fmt::Write,
+    E: Enviroment,
+{
+    Options::default().expand_into(out, env, input)
+}
+
+pub fn expand_to_writer<W, E>(out: &mut W, env: &mut E, input: &str) -> error::Result<()>
+where
+    W: io::Write,
+    E: Enviroment,
+{
+    Options::default().expand_to_writer(out, env, input)
+}
+
+pub fn expand_reader<R: Read>(reader: R) -> error::Result {
+    expand_reader_with(&mut ProcessEnv, reader)
+}
+
+pub fn expand_reader_with<E, R>(env: &mut E, reader: R) -> error::Result
+where
+    E: Enviroment,
+    R: Read,
+{
+    Options::default().expand_reader_with(env, reader)
+}
+
+pub fn expand_file<P: AsRef<Path>>(path: P) -> error::Result {
+    expand_file_with(&mut ProcessEnv, path)
+}
+
+pub fn expand_file_with<E, P>(env: &mut E, path: P) -> error::Result
+where
+    E: Enviroment,
+    P: AsRef<Path>,
+{
+    Options::default().expand_file_with(env, path)
 }
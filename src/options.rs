@@ -0,0 +1,235 @@
+use std::{
+    borrow::Cow,
+    ffi::{OsStr, OsString},
+    fmt,
+    fs::File,
+    io::{self, Read},
+    path::Path,
+};
+
+use crate::{
+    ast,
+    env::{Enviroment, ProcessEnv},
+    error,
+    filter::Filter,
+    template::Template,
+    token, variables,
+};
+
+/// How deeply substitutions may be nested unless [`Options::max_depth`] says
+/// otherwise.
+pub const DEFAULT_MAX_DEPTH: usize = 64;
+
+/// Settings for expanding a template, built up from the defaults:
+///
+/// ```
+/// use expandvars::{env::FakeEnv, options::Options};
+///
+/// let mut env = FakeEnv::empty().with("FOO", "test");
+/// let res = Options::new().sigil('%').expand_with(&mut env, "%FOO,$HOME");
+/// assert_eq!(res.unwrap(), "test,$HOME");
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Options {
+    pub(crate) sigil: char,
+    pub(crate) lossy: bool,
+    pub(crate) nounset: bool,
+    pub(crate) max_depth: usize,
+    pub(crate) max_output: Option<usize>,
+    pub(crate) filter: Option<Filter>,
+}
+
+impl Options {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the symbol that introduces a variable, `$` by default.
+    pub fn sigil(mut self, sigil: char) -> Self {
+        self.sigil = sigil;
+        self
+    }
+
+    /// Whether to replace invalid UTF-8 in environment values with U+FFFD when
+    /// they have to be decoded, rather than failing with
+    /// [`VarError::NotUnicode`](std::env::VarError::NotUnicode). Off by default.
+    pub fn lossy(mut self, lossy: bool) -> Self {
+        self.lossy = lossy;
+        self
+    }
+
+    /// Whether referring to an unset variable without a default is an
+    /// [`UnsetVariable`](error::Error::UnsetVariable) error rather than
+    /// expanding to nothing, like `set -u` in a shell. Off by default.
+    pub fn nounset(mut self, nounset: bool) -> Self {
+        self.nounset = nounset;
+        self
+    }
+
+    /// Sets how deeply `${...}` and braces inside operator words may be nested,
+    /// as in `${A:-${B:-{}}}`, before failing with
+    /// [`TooDeep`](error::Error::TooDeep). This keeps hostile templates from
+    /// exhausting the stack. [`DEFAULT_MAX_DEPTH`] by default.
+    pub fn max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+
+    /// Caps the expansion at `max_output` bytes, failing with
+    /// [`OutputTooLarge`](error::Error::OutputTooLarge) as soon as it would
+    /// write past that, rather than once it is done. Unlimited by default.
+    pub fn max_output(mut self, max_output: usize) -> Self {
+        self.max_output = Some(max_output);
+        self
+    }
+
+    /// Restricts which variables may be expanded, see [`Filter`].
+    pub fn filter(mut self, filter: Filter) -> Self {
+        self.filter = Some(filter);
+        self
+    }
+
+    /// Parses `input` into a [`Template`] that can be rendered repeatedly.
+    pub fn template<'a>(&self, input: &'a str) -> error::Result<Template<'a>> {
+        Template::with_options(input, self.clone())
+    }
+
+    /// Parses `input` into its syntax tree without expanding it.
+    pub fn parse<'a>(&self, input: &'a str) -> error::Result<Vec<ast::Node<'a>>> {
+        self.template(input)?.nodes()
+    }
+
+    /// Lists the variables `input` refers to without expanding it.
+    pub fn variables<'a>(
+        &self,
+        input: &'a str,
+    ) -> error::Result<Vec<variables::Variable<'a>>> {
+        self.template(input)?.variables()
+    }
+
+    pub fn expand(&self, input: &str) -> error::Result {
+        self.expand_with(&mut ProcessEnv, input)
+    }
+
+    pub fn expand_with<E>(&self, env: &mut E, input: &str) -> error::Result
+    where
+        E: Enviroment,
+    {
+        let mut res = String::new();
+        self.expand_into(&mut res, env, input)?;
+        Ok(res)
+    }
+
+    pub fn expand_cow<'a>(&self, input: &'a str) -> error::Result<Cow<'a, str>> {
+        self.expand_cow_with(&mut ProcessEnv, input)
+    }
+
+    /// Like [`Options::expand_with`], but borrows `input` instead of allocating
+    /// when there is nothing to substitute.
+    pub fn expand_cow_with<'a, E>(
+        &self,
+        env: &mut E,
+        input: &'a str,
+    ) -> error::Result<Cow<'a, str>>
+    where
+        E: Enviroment,
+    {
+        self.template(input)?.render_cow(env)
+    }
+
+    /// Writes the expansion into `out` as it goes, rather than collecting it into
+    /// a new string.
+    pub fn expand_into<W, E>(
+        &self,
+        out: &mut W,
+        env: &mut E,
+        input: &str,
+    ) -> error::Result<()>
+    where
+        W: fmt::Write,
+        E: Enviroment,
+    {
+        self.template(input)?.render_into(out, env)
+    }
+
+    /// Like [`Options::expand_into`], for byte sinks such as files and sockets.
+    pub fn expand_to_writer<W, E>(
+        &self,
+        out: &mut W,
+        env: &mut E,
+        input: &str,
+    ) -> error::Result<()>
+    where
+        W: io::Write,
+        E: Enviroment,
+    {
+        self.template(input)?.render_to_writer(out, env)
+    }
+
+    pub fn expand_bytes(&self, input: &[u8]) -> error::Result<Vec<u8>> {
+        self.expand_bytes_with(&mut ProcessEnv, input)
+    }
+
+    /// Expands a template that need not be valid UTF-8, see
+    /// [`Template::from_bytes`].
+    pub fn expand_bytes_with<E>(
+        &self,
+        env: &mut E,
+        input: &[u8],
+    ) -> error::Result<Vec<u8>>
+    where
+        E: Enviroment,
+    {
+        Template::from_bytes(input, self.clone())?.render_bytes(env)
+    }
+
+    pub fn expand_os(&self, input: &OsStr) -> error::Result<OsString> {
+        self.expand_os_with(&mut ProcessEnv, input)
+    }
+
+    /// Expands an `OsStr` without going through UTF-8 on Unix, see
+    /// [`Template::render_os`].
+    pub fn expand_os_with<E>(
+        &self,
+        env: &mut E,
+        input: &OsStr,
+    ) -> error::Result<OsString>
+    where
+        E: Enviroment,
+    {
+        let input = token::os_to_bytes(input, self.lossy)?;
+        Template::from_bytes(&input, self.clone())?.render_os(env)
+    }
+
+    /// Reads the whole template from `reader` before expanding it.
+    pub fn expand_reader_with<E, R>(&self, env: &mut E, mut reader: R) -> error::Result
+    where
+        E: Enviroment,
+        R: Read,
+    {
+        let mut input = String::new();
+        reader.read_to_string(&mut input)?;
+        self.expand_with(env, &input)
+    }
+
+    pub fn expand_file_with<E, P>(&self, env: &mut E, path: P) -> error::Result
+    where
+        E: Enviroment,
+        P: AsRef<Path>,
+    {
+        self.expand_reader_with(env, File::open(path)?)
+    }
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            sigil: '$',
+            lossy: false,
+            nounset: false,
+            max_depth: DEFAULT_MAX_DEPTH,
+            max_output: None,
+            filter: None,
+        }
+    }
+}
@@ -1,90 +1,457 @@
-use crate::error::Error;
-use crate::token::Token;
+use std::cell::Cell;
+
+use crate::error::{Error, Span};
+use crate::options::DEFAULT_MAX_DEPTH;
+use crate::token::{Operator, Param, Token};
 use nom::branch::alt;
-use nom::bytes::complete::tag;
-use nom::bytes::complete::take_while1;
+use nom::bytes::complete::{tag, take_while, take_while1};
 use nom::character::complete::char;
 use nom::character::is_alphanumeric;
-use nom::combinator::map;
+use nom::combinator::{consumed, map, opt, value};
+use nom::error::ErrorKind;
 use nom::multi::fold_many0;
-use nom::sequence::{delimited, preceded, separated_pair};
-use nom::IResult;
+use nom::sequence::{preceded, tuple};
+use nom::Offset;
+
+/// Parser errors. Unlike `Nom`, the other variants are raised as
+/// `nom::Err::Failure` and abort parsing instead of trying another branch.
+///
+/// They hold the part of the input the error is about. Inside `${`, they are
+/// raised with an empty slice at the point of failure, which
+/// [`parse_variable`] then widens to start at the sigil.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum ParseError<I> {
+    Nom(I, ErrorKind),
+    MissingEscapedChar(I),
+    MissingClosingBrace(I),
+    BadSubstitution(I),
+    TooDeep(I),
+}
+
+impl<I> nom::error::ParseError<I> for ParseError<I> {
+    fn from_error_kind(input: I, kind: ErrorKind) -> Self {
+        Self::Nom(input, kind)
+    }
+
+    fn append(_: I, _: ErrorKind, other: Self) -> Self {
+        other
+    }
+}
+
+type IResult<I, O> = nom::IResult<I, O, ParseError<I>>;
+
+/// Settings the grammar is parsed with, along with how deeply nested the
+/// parser currently is.
+#[derive(Debug, Clone)]
+pub(crate) struct Context {
+    sigil: char,
+    sigil_bytes: [u8; 4],
+    max_depth: usize,
+    depth: Cell<usize>,
+}
+
+impl Context {
+    pub(crate) fn new(sigil: char) -> Self {
+        let mut sigil_bytes = [0; 4];
+        sigil.encode_utf8(&mut sigil_bytes);
+        Self {
+            sigil,
+            sigil_bytes,
+            max_depth: DEFAULT_MAX_DEPTH,
+            depth: Cell::new(0),
+        }
+    }
+
+    pub(crate) fn max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+
+    fn sigil_bytes(&self) -> &[u8] {
+        &self.sigil_bytes[..self.sigil.len_utf8()]
+    }
+
+    /// Runs `f` one level of nesting deeper, failing with `span` if that goes
+    /// past the limit.
+    fn nested<'a, O>(
+        &self,
+        span: &'a [u8],
+        f: impl FnOnce() -> IResult<&'a [u8], O>,
+    ) -> IResult<&'a [u8], O> {
+        let depth = self.depth.get() + 1;
+        if depth > self.max_depth {
+            return Err(nom::Err::Failure(ParseError::TooDeep(span)));
+        }
+        self.depth.set(depth);
+        let res = f();
+        self.depth.set(depth - 1);
+        res
+    }
+}
+
+impl Default for Context {
+    fn default() -> Self {
+        Self::new('$')
+    }
+}
 
-fn is_variable_name(c: u8) -> bool {
+pub(crate) fn is_variable_name(c: u8) -> bool {
     c == b'_' || is_alphanumeric(c)
 }
 
-fn parse_constant(i: &[u8]) -> IResult<&[u8], Token> {
-    map(take_while1(|c| c != b'$' && c != b'}'), Token::Const)(i)
+fn sigil<'a>(i: &'a [u8], ctx: &Context) -> IResult<&'a [u8], &'a [u8]> {
+    tag(ctx.sigil_bytes())(i)
+}
+
+/// Takes input up to the sigil or any of the `special` bytes.
+fn take_till_special<'a>(
+    i: &'a [u8],
+    ctx: &Context,
+    special: &[u8],
+) -> IResult<&'a [u8], &'a [u8]> {
+    let sigil = ctx.sigil_bytes();
+    let end = (0..i.len())
+        .find(|&n| special.contains(&i[n]) || i[n..].starts_with(sigil))
+        .unwrap_or(i.len());
+
+    if end == 0 {
+        return Err(nom::Err::Error(ParseError::Nom(i, ErrorKind::TakeTill1)));
+    }
+    Ok((&i[end..], &i[..end]))
+}
+
+fn parse_constant<'a>(i: &'a [u8], ctx: &Context) -> IResult<&'a [u8], Token<'a>> {
+    map(|i| take_till_special(i, ctx, b"}\\"), Token::Const)(i)
+}
+
+/// Backslashes only escape when followed by the sigil, where each pair yields
+/// one backslash and an odd one out makes the sigil literal. Anything else, such
+/// as a Windows path, is kept as is.
+fn parse_escape<'a>(i: &'a [u8], ctx: &Context) -> IResult<&'a [u8], Token<'a>> {
+    let (rest, run) = take_while1(|c| c == b'\\')(i)?;
+    let n = run.len();
+    let sigil = ctx.sigil_bytes();
+    if rest.starts_with(sigil) && n % 2 == 1 {
+        let end = n + sigil.len();
+        Ok((&i[end..], Token::Escape(&i[..end])))
+    } else if rest.starts_with(sigil) {
+        Ok((rest, Token::Escape(run)))
+    } else if rest.is_empty() && n % 2 == 1 {
+        Err(nom::Err::Failure(ParseError::MissingEscapedChar(
+            &i[n - 1..],
+        )))
+    } else {
+        Ok((rest, Token::Const(run)))
+    }
+}
+
+/// What follows the sigil, before it is known where the whole reference ends.
+#[derive(Debug, PartialEq, Eq)]
+enum Body<'a> {
+    Pid,
+    Param(bool, Parameter<'a>),
+}
+
+/// A name and the operator applied to it.
+type Parameter<'a> = (&'a [u8], Option<Operator<'a>>);
+
+fn parse_pid<'a>(i: &'a [u8], ctx: &Context) -> IResult<&'a [u8], Body<'a>> {
+    map(|i| sigil(i, ctx), |_| Body::Pid)(i)
+}
+
+fn parse_variable_name(i: &[u8]) -> IResult<&[u8], Parameter<'_>> {
+    map(take_while1(is_variable_name), |name| (name, None))(i)
+}
+
+/// Parses `op` or `:op`, yielding whether the colon form was used.
+fn parse_operator<'a>(op: char) -> impl FnMut(&'a [u8]) -> IResult<&'a [u8], bool> {
+    alt((
+        value(true, preceded(char(':'), char(op))),
+        value(false, char(op)),
+    ))
+}
+
+fn parse_word_constant<'a>(i: &'a [u8], ctx: &Context) -> IResult<&'a [u8], Token<'a>> {
+    map(|i| take_till_special(i, ctx, b"{}\\"), Token::Const)(i)
+}
+
+/// Braces inside a word must be balanced, so that `${FOO:-{}}` defaults to `{}`.
+fn parse_braced_word<'a>(
+    i: &'a [u8],
+    ctx: &Context,
+) -> IResult<&'a [u8], Vec<Token<'a>>> {
+    let start = i;
+    let (i, _) = char('{')(i)?;
+    let (i, word) = ctx.nested(&start[..1], || parse_word(i, ctx))?;
+    let end = i;
+    let (i, _) = closing_brace(i)?;
+
+    let mut tokens = Vec::with_capacity(word.len() + 2);
+    tokens.push(Token::Char(&start[..1], '{'));
+    tokens.extend(word);
+    tokens.push(Token::Char(&end[..1], '}'));
+    Ok((i, tokens))
+}
+
+fn parse_word<'a>(i: &'a [u8], ctx: &Context) -> IResult<&'a [u8], Vec<Token<'a>>> {
+    fold_many0(
+        alt((
+            map(
+                alt((
+                    |i| parse_variable(i, ctx),
+                    |i| parse_escape(i, ctx),
+                    |i| parse_word_constant(i, ctx),
+                    |i| parse_dollar(i, ctx),
+                )),
+                |tok| vec![tok],
+            ),
+            |i| parse_braced_word(i, ctx),
+        )),
+        Vec::new,
+        |mut word, tokens| {
+            word.extend(tokens);
+            word
+        },
+    )(i)
+}
+
+fn parse_variable_name_with_default<'a>(
+    i: &'a [u8],
+    ctx: &Context,
+) -> IResult<&'a [u8], Parameter<'a>> {
+    map(
+        tuple((take_while1(is_variable_name), parse_operator('-'), |i| {
+            parse_word(i, ctx)
+        })),
+        |(name, colon, default)| (name, Some(Operator::Default(colon, default))),
+    )(i)
 }
 
-fn parse_pid(i: &[u8]) -> IResult<&[u8], Token> {
-    map(char('$'), |_| Token::Pid)(i)
+fn parse_variable_name_with_assign<'a>(
+    i: &'a [u8],
+    ctx: &Context,
+) -> IResult<&'a [u8], Parameter<'a>> {
+    map(
+        tuple((take_while1(is_variable_name), parse_operator('='), |i| {
+            parse_word(i, ctx)
+        })),
+        |(name, colon, default)| (name, Some(Operator::Assign(colon, default))),
+    )(i)
 }
 
-fn parse_variable_name(i: &[u8]) -> IResult<&[u8], Token> {
-    map(take_while1(is_variable_name), Token::Var)(i)
+fn parse_variable_name_with_alternate<'a>(
+    i: &'a [u8],
+    ctx: &Context,
+) -> IResult<&'a [u8], Parameter<'a>> {
+    map(
+        tuple((take_while1(is_variable_name), parse_operator('+'), |i| {
+            parse_word(i, ctx)
+        })),
+        |(name, colon, alternate)| (name, Some(Operator::Alternate(colon, alternate))),
+    )(i)
 }
 
-fn parse_variable_name_with_default(i: &[u8]) -> IResult<&[u8], Token> {
+fn parse_variable_name_with_error<'a>(
+    i: &'a [u8],
+    ctx: &Context,
+) -> IResult<&'a [u8], Parameter<'a>> {
     map(
-        separated_pair(
-            take_while1(is_variable_name),
-            alt((tag("-"), tag(":-"))),
-            alt((parse_fragment, map(tag(""), Token::Const))),
-        ),
-        |(name, default)| Token::VarWithDefault(name, Box::new(default)),
+        tuple((take_while1(is_variable_name), parse_operator('?'), |i| {
+            parse_word(i, ctx)
+        })),
+        |(name, colon, message)| (name, Some(Operator::Error(colon, message))),
     )(i)
 }
 
-fn parse_variable_body(i: &[u8]) -> IResult<&[u8], Token> {
+fn parse_operand(i: &[u8]) -> IResult<&[u8], &[u8]> {
+    take_while(|c| c != b':' && c != b'}')(i)
+}
+
+fn parse_variable_name_with_substring(i: &[u8]) -> IResult<&[u8], Parameter<'_>> {
+    let (rest, (name, offset, length)) = tuple((
+        take_while1(is_variable_name),
+        preceded(char(':'), parse_operand),
+        opt(preceded(char(':'), parse_operand)),
+    ))(i)?;
+
+    // `${FOO:}` has neither an offset nor a length.
+    if offset.is_empty() && length.is_none() && rest.first() == Some(&b'}') {
+        return Err(nom::Err::Failure(ParseError::BadSubstitution(&rest[..0])));
+    }
+
+    Ok((rest, (name, Some(Operator::Substring(offset, length)))))
+}
+
+fn parse_variable_body<'a>(i: &'a [u8], ctx: &Context) -> IResult<&'a [u8], Body<'a>> {
+    alt((
+        |i| parse_pid(i, ctx),
+        map(parse_variable_name, |param| Body::Param(false, param)),
+    ))(i)
+}
+
+fn parse_parameter<'a>(i: &'a [u8], ctx: &Context) -> IResult<&'a [u8], Parameter<'a>> {
     alt((
-        parse_pid,
-        parse_variable_name_with_default,
+        |i| parse_variable_name_with_default(i, ctx),
+        |i| parse_variable_name_with_assign(i, ctx),
+        |i| parse_variable_name_with_alternate(i, ctx),
+        |i| parse_variable_name_with_error(i, ctx),
+        parse_variable_name_with_substring,
         parse_variable_name,
     ))(i)
 }
 
-fn parse_braced_variable_body(i: &[u8]) -> IResult<&[u8], Token> {
-    delimited(char('{'), parse_variable_body, char('}'))(i)
+fn parse_indirect<'a>(i: &'a [u8], ctx: &Context) -> IResult<&'a [u8], Body<'a>> {
+    map(preceded(char('!'), |i| parse_parameter(i, ctx)), |param| {
+        Body::Param(true, param)
+    })(i)
 }
 
-fn parse_dollar(i: &[u8]) -> IResult<&[u8], Token> {
-    map(char('$'), Token::Char)(i)
+/// Once inside `${`, anything but a well formed substitution is an error.
+fn closing_brace(i: &[u8]) -> IResult<&[u8], char> {
+    match i.first() {
+        Some(b'}') => Ok((&i[1..], '}')),
+        Some(_) => Err(nom::Err::Failure(ParseError::BadSubstitution(&i[..0]))),
+        None => Err(nom::Err::Failure(ParseError::MissingClosingBrace(i))),
+    }
 }
 
-fn parse_closing_brace(i: &[u8]) -> IResult<&[u8], Token> {
-    map(char('}'), Token::Char)(i)
+fn parse_braced_variable_body<'a>(
+    i: &'a [u8],
+    ctx: &Context,
+) -> IResult<&'a [u8], Body<'a>> {
+    let start = i;
+    let (i, _) = char('{')(i)?;
+    let res = ctx.nested(&start[..0], || {
+        alt((
+            |i| parse_pid(i, ctx),
+            |i| parse_indirect(i, ctx),
+            map(
+                |i| parse_parameter(i, ctx),
+                |param| Body::Param(false, param),
+            ),
+        ))(i)
+    });
+    match res {
+        Ok((i, body)) => {
+            let (i, _) = closing_brace(i)?;
+            Ok((i, body))
+        }
+        Err(nom::Err::Error(_)) if i.is_empty() => {
+            Err(nom::Err::Failure(ParseError::MissingClosingBrace(i)))
+        }
+        Err(nom::Err::Error(_)) => {
+            Err(nom::Err::Failure(ParseError::BadSubstitution(&i[..0])))
+        }
+        Err(e) => Err(e),
+    }
 }
 
-fn parse_variable(i: &[u8]) -> IResult<&[u8], Token> {
-    preceded(
-        char('$'),
-        alt((parse_braced_variable_body, parse_variable_body, parse_pid)),
-    )(i)
+fn parse_dollar<'a>(i: &'a [u8], ctx: &Context) -> IResult<&'a [u8], Token<'a>> {
+    map(|i| sigil(i, ctx), |s| Token::Char(s, ctx.sigil))(i)
+}
+
+fn parse_closing_brace(i: &[u8]) -> IResult<&[u8], Token<'_>> {
+    map(tag("}"), |s| Token::Char(s, '}'))(i)
+}
+
+/// Widens an error raised inside `${` to span from the sigil at the start of
+/// `i` up to and including the character it failed at.
+fn widen<'a>(
+    i: &'a [u8],
+    e: nom::Err<ParseError<&'a [u8]>>,
+) -> nom::Err<ParseError<&'a [u8]>> {
+    let span = |at: &'a [u8]| {
+        let start = i.offset(at);
+        let width = at.iter().skip(1).take_while(|&&c| c & 0xC0 == 0x80).count();
+        &i[..(start + 1 + width).min(i.len())]
+    };
+    match e {
+        nom::Err::Failure(ParseError::MissingClosingBrace(at)) if at.is_empty() => {
+            nom::Err::Failure(ParseError::MissingClosingBrace(span(at)))
+        }
+        nom::Err::Failure(ParseError::BadSubstitution(at)) if at.is_empty() => {
+            nom::Err::Failure(ParseError::BadSubstitution(span(at)))
+        }
+        nom::Err::Failure(ParseError::TooDeep(at)) if at.is_empty() => {
+            nom::Err::Failure(ParseError::TooDeep(span(at)))
+        }
+        e => e,
+    }
+}
+
+fn parse_variable<'a>(i: &'a [u8], ctx: &Context) -> IResult<&'a [u8], Token<'a>> {
+    let (rest, (source, body)) = consumed(preceded(
+        |i| sigil(i, ctx),
+        alt((
+            |i| parse_braced_variable_body(i, ctx),
+            |i| parse_variable_body(i, ctx),
+        )),
+    ))(i)
+    .map_err(|e| widen(i, e))?;
+
+    let tok = match body {
+        Body::Pid => Token::Pid(source),
+        Body::Param(indirect, (name, operator)) => Token::Param(Param {
+            source,
+            name,
+            indirect,
+            operator,
+        }),
+    };
+    Ok((rest, tok))
 }
 
-fn parse_fragment(i: &[u8]) -> IResult<&[u8], Token> {
+fn parse_fragment<'a>(i: &'a [u8], ctx: &Context) -> IResult<&'a [u8], Token<'a>> {
     alt((
-        parse_variable,
-        parse_constant,
-        parse_dollar,
+        |i| parse_variable(i, ctx),
+        |i| parse_escape(i, ctx),
+        |i| parse_constant(i, ctx),
+        |i| parse_dollar(i, ctx),
         parse_closing_brace,
     ))(i)
 }
 
-pub(crate) fn parse<'a>(
-    i: &'a [u8],
-) -> IResult<&'a [u8], Result<Vec<Token<'a>>, Error>> {
-    fold_many0(
-        parse_fragment,
-        || Ok(Vec::new()),
-        |tokens, tok| {
-            let mut tokens = tokens?;
+/// Parses the whole of `i`, or reports where it could not go any further.
+pub(crate) fn parse<'a>(i: &'a [u8], ctx: &Context) -> Result<Vec<Token<'a>>, Error> {
+    let res = fold_many0(
+        |i| parse_fragment(i, ctx),
+        Vec::new,
+        |mut tokens, tok| {
             tokens.push(tok);
-            Ok(tokens)
+            tokens
         },
-    )(i)
+    )(i);
+
+    let input = || String::from_utf8_lossy(i).into_owned();
+    let e = match res {
+        Ok(([], tokens)) => return Ok(tokens),
+        Ok((rest, _)) => ParseError::Nom(rest, ErrorKind::Eof),
+        Err(nom::Err::Incomplete(_)) => ParseError::Nom(&i[i.len()..], ErrorKind::Eof),
+        Err(nom::Err::Error(e) | nom::Err::Failure(e)) => e,
+    };
+    Err(match e {
+        ParseError::Nom(at, _) => Error::Parse {
+            input: input(),
+            position: Span::of(i, at),
+        },
+        ParseError::MissingEscapedChar(at) => Error::MissingEscapedChar {
+            input: input(),
+            position: Span::of(i, at),
+        },
+        ParseError::MissingClosingBrace(at) => Error::MissingClosingBrace {
+            input: input(),
+            position: Span::of(i, at),
+        },
+        ParseError::BadSubstitution(at) => Error::BadSubstitution {
+            input: input(),
+            position: Span::of(i, at),
+        },
+        ParseError::TooDeep(at) => Error::TooDeep {
+            input: input(),
+            max_depth: ctx.max_depth,
+            position: Span::of(i, at),
+        },
+    })
 }
 
 #[cfg(test)]
@@ -92,70 +459,352 @@ mod tests {
 
     use super::*;
 
+    fn param<'a>(
+        source: &'a [u8],
+        name: &'a [u8],
+        operator: Option<Operator<'a>>,
+    ) -> Token<'a> {
+        Token::Param(Param {
+            source,
+            name,
+            indirect: false,
+            operator,
+        })
+    }
+
     #[test]
     fn test_parse_constant() {
+        let ctx = Context::default();
+
         assert_eq!(
-            parse_constant(b"foo.bar").unwrap().1,
+            parse_constant(b"foo.bar", &ctx).unwrap().1,
             Token::Const(b"foo.bar")
         );
     }
 
     #[test]
     fn test_parse_variable() {
-        assert_eq!(parse_variable(b"$var").unwrap().1, Token::Var(b"var"));
+        let ctx = Context::default();
+
+        assert_eq!(
+            parse_variable(b"$var", &ctx).unwrap().1,
+            param(b"$var", b"var", None)
+        );
+        assert_eq!(
+            parse_variable(b"${$}", &ctx).unwrap().1,
+            Token::Pid(b"${$}")
+        );
     }
 
     #[test]
     fn test_parse_variable_with_default() {
+        let ctx = Context::default();
+
+        assert_eq!(
+            parse_variable_name_with_default(b"var-default", &ctx)
+                .unwrap()
+                .1,
+            (
+                &b"var"[..],
+                Some(Operator::Default(false, vec![Token::Const(b"default")]))
+            )
+        );
+
         assert_eq!(
-            parse_variable_name_with_default(b"var-default").unwrap().1,
-            Token::VarWithDefault(b"var", Box::new(Token::Const(b"default")))
+            parse_variable_name_with_default(b"var-", &ctx).unwrap().1,
+            (&b"var"[..], Some(Operator::Default(false, vec![])))
         );
 
         assert_eq!(
-            parse_variable_name_with_default(b"var-").unwrap().1,
-            Token::VarWithDefault(b"var", Box::new(Token::Const(b"")))
+            parse_variable_name_with_default(b"var:-", &ctx).unwrap().1,
+            (&b"var"[..], Some(Operator::Default(true, vec![])))
         );
 
         assert_eq!(
-            parse_variable_name_with_default(b"var:-").unwrap().1,
-            Token::VarWithDefault(b"var", Box::new(Token::Const(b"")))
+            parse_variable_name_with_default(b"var:-$foo.bar", &ctx)
+                .unwrap()
+                .1,
+            (
+                &b"var"[..],
+                Some(Operator::Default(
+                    true,
+                    vec![param(b"$foo", b"foo", None), Token::Const(b".bar")]
+                ))
+            )
+        );
+    }
+
+    #[test]
+    fn test_parse_variable_with_assign() {
+        let ctx = Context::default();
+
+        assert_eq!(
+            parse_variable_name_with_assign(b"var=default", &ctx)
+                .unwrap()
+                .1,
+            (
+                &b"var"[..],
+                Some(Operator::Assign(false, vec![Token::Const(b"default")]))
+            )
+        );
+
+        assert_eq!(
+            parse_variable_name_with_assign(b"var:=", &ctx).unwrap().1,
+            (&b"var"[..], Some(Operator::Assign(true, vec![])))
+        );
+    }
+
+    #[test]
+    fn test_parse_variable_with_alternate() {
+        let ctx = Context::default();
+
+        assert_eq!(
+            parse_variable_name_with_alternate(b"var+alt", &ctx)
+                .unwrap()
+                .1,
+            (
+                &b"var"[..],
+                Some(Operator::Alternate(false, vec![Token::Const(b"alt")]))
+            )
+        );
+
+        assert_eq!(
+            parse_variable_name_with_alternate(b"var:+${var};", &ctx)
+                .unwrap()
+                .1,
+            (
+                &b"var"[..],
+                Some(Operator::Alternate(
+                    true,
+                    vec![param(b"${var}", b"var", None), Token::Const(b";")]
+                ))
+            )
+        );
+    }
+
+    #[test]
+    fn test_parse_variable_with_error() {
+        let ctx = Context::default();
+
+        assert_eq!(
+            parse_variable_name_with_error(b"var?", &ctx).unwrap().1,
+            (&b"var"[..], Some(Operator::Error(false, vec![])))
+        );
+
+        assert_eq!(
+            parse_variable_name_with_error(b"var:?custom error", &ctx)
+                .unwrap()
+                .1,
+            (
+                &b"var"[..],
+                Some(Operator::Error(true, vec![Token::Const(b"custom error")]))
+            )
+        );
+    }
+
+    #[test]
+    fn test_parse_variable_with_substring() {
+        assert_eq!(
+            parse_variable_name_with_substring(b"var:1").unwrap().1,
+            (&b"var"[..], Some(Operator::Substring(b"1", None)))
+        );
+
+        assert_eq!(
+            parse_variable_name_with_substring(b"var: 1 : 2 ")
+                .unwrap()
+                .1,
+            (&b"var"[..], Some(Operator::Substring(b" 1 ", Some(b" 2 "))))
+        );
+
+        assert_eq!(
+            parse_variable_name_with_substring(b"var::").unwrap().1,
+            (&b"var"[..], Some(Operator::Substring(b"", Some(b""))))
+        );
+
+        assert_eq!(
+            parse_variable_name_with_substring(b"var:").unwrap().1,
+            (&b"var"[..], Some(Operator::Substring(b"", None)))
+        );
+
+        assert_eq!(
+            parse_variable_name_with_substring(b"var:}"),
+            Err(nom::Err::Failure(ParseError::BadSubstitution(&b""[..])))
+        );
+    }
+
+    #[test]
+    fn test_parse_indirect() {
+        let ctx = Context::default();
+
+        assert_eq!(
+            parse_indirect(b"!var", &ctx).unwrap().1,
+            Body::Param(true, (&b"var"[..], None))
+        );
+
+        assert_eq!(
+            parse_indirect(b"!var-default", &ctx).unwrap().1,
+            Body::Param(
+                true,
+                (
+                    &b"var"[..],
+                    Some(Operator::Default(false, vec![Token::Const(b"default")]))
+                )
+            )
         );
     }
 
     #[test]
     fn test_parse_braced_variable_body() {
+        let ctx = Context::default();
+
+        assert_eq!(
+            parse_braced_variable_body(b"{var}", &ctx).unwrap().1,
+            Body::Param(false, (&b"var"[..], None))
+        );
+    }
+
+    #[test]
+    fn test_parse_escape() {
+        let ctx = Context::default();
+
+        assert_eq!(
+            parse_escape(b"\\$FOO", &ctx).unwrap(),
+            (&b"FOO"[..], Token::Escape(b"\\$"))
+        );
+        assert_eq!(
+            parse_escape(b"\\\\$FOO", &ctx).unwrap(),
+            (&b"$FOO"[..], Token::Escape(b"\\\\"))
+        );
+        assert_eq!(
+            parse_escape(b"\\\\\\$FOO", &ctx).unwrap(),
+            (&b"FOO"[..], Token::Escape(b"\\\\\\$"))
+        );
+        assert_eq!(
+            parse_escape(b"\\some", &ctx).unwrap(),
+            (&b"some"[..], Token::Const(b"\\"))
+        );
+        assert_eq!(
+            parse_escape(b"\\\\", &ctx).unwrap(),
+            (&b""[..], Token::Const(b"\\\\"))
+        );
+        assert_eq!(
+            parse_escape(b"\\", &ctx),
+            Err(nom::Err::Failure(ParseError::MissingEscapedChar(
+                &b"\\"[..]
+            )))
+        );
+    }
+
+    #[test]
+    fn test_parse_braced_word() {
+        let ctx = Context::default();
+
+        assert_eq!(
+            parse_word(b"{$var}}", &ctx).unwrap(),
+            (
+                &b"}"[..],
+                vec![
+                    Token::Char(b"{", '{'),
+                    param(b"$var", b"var", None),
+                    Token::Char(b"}", '}')
+                ]
+            )
+        );
+
+        assert_eq!(
+            parse_word(b"{{}", &ctx),
+            Err(nom::Err::Failure(ParseError::MissingClosingBrace(&b""[..])))
+        );
+    }
+
+    #[test]
+    fn test_closing_brace() {
+        let ctx = Context::default();
+
+        assert_eq!(
+            parse_braced_variable_body(b"{var", &ctx),
+            Err(nom::Err::Failure(ParseError::MissingClosingBrace(&b""[..])))
+        );
+
+        assert_eq!(
+            parse_braced_variable_body(b"{var:1:2:3}", &ctx),
+            Err(nom::Err::Failure(ParseError::BadSubstitution(&b""[..])))
+        );
+
+        assert_eq!(
+            parse_braced_variable_body(b"{}", &ctx),
+            Err(nom::Err::Failure(ParseError::BadSubstitution(&b""[..])))
+        );
+
+        assert_eq!(
+            parse_variable(b"${var:1:2:3} ", &ctx),
+            Err(nom::Err::Failure(ParseError::BadSubstitution(
+                &b"${var:1:2:"[..]
+            )))
+        );
+
+        assert_eq!(
+            parse_variable(b"${var:-${foo", &ctx),
+            Err(nom::Err::Failure(ParseError::MissingClosingBrace(
+                &b"${foo"[..]
+            )))
+        );
+    }
+
+    #[test]
+    fn test_sigil() {
+        let ctx = Context::new('£');
+
         assert_eq!(
-            parse_braced_variable_body(b"{var}").unwrap().1,
-            Token::Var(b"var")
+            parse("£{var}:$HOME:£:\\£var".as_bytes(), &ctx).unwrap(),
+            vec![
+                param("£{var}".as_bytes(), b"var", None),
+                Token::Const(b":$HOME:"),
+                Token::Char("£".as_bytes(), '£'),
+                Token::Const(b":"),
+                Token::Escape("\\£".as_bytes()),
+                Token::Const(b"var"),
+            ]
         );
     }
 
     #[test]
     fn test_dollar() {
-        assert_eq!(parse_dollar(b"$").unwrap().1, Token::Char('$'));
+        let ctx = Context::default();
+
+        assert_eq!(parse_dollar(b"$", &ctx).unwrap().1, Token::Char(b"$", '$'));
     }
 
     #[test]
     fn test_parse_fragment() {
-        assert_eq!(parse_fragment(b"foo").unwrap().1, Token::Const(b"foo"));
-        assert_eq!(parse_fragment(b"$var").unwrap().1, Token::Var(b"var"));
+        let ctx = Context::default();
+
+        assert_eq!(
+            parse_fragment(b"foo", &ctx).unwrap().1,
+            Token::Const(b"foo")
+        );
+        assert_eq!(
+            parse_fragment(b"$var", &ctx).unwrap().1,
+            param(b"$var", b"var", None)
+        );
     }
 
     #[test]
     fn test_pars() {
+        let ctx = Context::default();
+
         use Token::*;
 
         assert_eq!(
-            parse(b"foo$var.foo.${var}}${var-}$").unwrap().1.unwrap(),
+            parse(b"foo$var.foo.${var}}${var-}$", &ctx).unwrap(),
             vec![
                 Const(b"foo"),
-                Var(b"var"),
+                param(b"$var", b"var", None),
                 Const(b".foo."),
-                Var(b"var"),
-                Char('}'),
-                VarWithDefault(b"var", Box::new(Const(b""))),
-                Char('$')
+                param(b"${var}", b"var", None),
+                Char(b"}", '}'),
+                param(b"${var-}", b"var", Some(Operator::Default(false, vec![]))),
+                Char(b"$", '$')
             ]
         );
     }
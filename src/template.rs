@@ -0,0 +1,161 @@
+use std::{borrow::Cow, ffi::OsString, fmt, io};
+
+use crate::{
+    ast, env::Enviroment, error, options::Options, parser, token, token::Token,
+    variables,
+};
+
+/// A template that is parsed once up front, so that it can be rendered against
+/// any number of environments without paying for parsing again.
+///
+/// ```
+/// use expandvars::{env::FakeEnv, template::Template};
+///
+/// let template = Template::new("${GREETING:-Hello}, $NAME!").unwrap();
+///
+/// let mut env = FakeEnv::empty().with("NAME", "world");
+/// assert_eq!(template.render(&mut env).unwrap(), "Hello, world!");
+///
+/// let mut env = env.with("GREETING", "Bye");
+/// assert_eq!(template.render(&mut env).unwrap(), "Bye, world!");
+/// ```
+#[derive(Debug, Clone)]
+pub struct Template<'a> {
+    input: &'a [u8],
+    tokens: Vec<Token<'a>>,
+    options: Options,
+}
+
+impl<'a> Template<'a> {
+    pub fn new(input: &'a str) -> error::Result<Self> {
+        Self::with_options(input, Options::default())
+    }
+
+    pub fn with_options(input: &'a str, options: Options) -> error::Result<Self> {
+        Self::from_bytes(input.as_bytes(), options)
+    }
+
+    /// Parses a template that need not be valid UTF-8. Only what has to be
+    /// decoded, such as variable names, must be.
+    pub fn from_bytes(input: &'a [u8], options: Options) -> error::Result<Self> {
+        let ctx = parser::Context::new(options.sigil).max_depth(options.max_depth);
+        let tokens = parser::parse(input, &ctx)?;
+        Ok(Self {
+            input,
+            tokens,
+            options,
+        })
+    }
+
+    pub fn options(&self) -> &Options {
+        &self.options
+    }
+
+    fn expand_into<E, O>(&self, env: &mut E, out: &mut O) -> error::Result<()>
+    where
+        E: Enviroment,
+        O: token::Output,
+    {
+        let ctx = token::Context::new(&self.options, self.input);
+        let mut out = token::Limited::new(out, &ctx);
+        token::expand_all_into(&self.tokens, env, &mut out, &ctx)
+    }
+
+    /// The syntax tree of the template.
+    pub fn nodes(&self) -> error::Result<Vec<ast::Node<'a>>> {
+        ast::from_tokens(self.input, &self.tokens)
+    }
+
+    /// The variables the template refers to, see [`variables::Requirement`].
+    pub fn variables(&self) -> error::Result<Vec<variables::Variable<'a>>> {
+        variables::from_tokens(self.input, &self.tokens)
+    }
+
+    pub fn render<E>(&self, env: &mut E) -> error::Result
+    where
+        E: Enviroment,
+    {
+        let mut res = String::new();
+        self.render_into(&mut res, env)?;
+        Ok(res)
+    }
+
+    /// Like [`Template::render`], but borrows from the template's input instead
+    /// of allocating when there is nothing to substitute.
+    pub fn render_cow<E>(&self, env: &mut E) -> error::Result<Cow<'a, str>>
+    where
+        E: Enviroment,
+    {
+        match self.tokens.as_slice() {
+            [] => Ok(Cow::Borrowed("")),
+            [Token::Const(s)]
+                if self.options.max_output.is_none_or(|max| s.len() <= max) =>
+            {
+                std::str::from_utf8(s)
+                    .map(Cow::Borrowed)
+                    .map_err(|_| error::Error::not_unicode(self.input, s))
+            }
+            _ => self.render(env).map(Cow::Owned),
+        }
+    }
+
+    pub fn render_into<W, E>(&self, out: &mut W, env: &mut E) -> error::Result<()>
+    where
+        W: fmt::Write,
+        E: Enviroment,
+    {
+        self.expand_into(env, &mut token::Text(out))
+    }
+
+    /// Renders into bytes, passing constant parts of the template through
+    /// untouched.
+    pub fn render_bytes<E>(&self, env: &mut E) -> error::Result<Vec<u8>>
+    where
+        E: Enviroment,
+    {
+        let mut res = Vec::new();
+        self.expand_into(env, &mut res)?;
+        Ok(res)
+    }
+
+    /// Renders into an `OsString`, carrying environment values through as is on
+    /// Unix, even when they are not valid UTF-8.
+    pub fn render_os<E>(&self, env: &mut E) -> error::Result<OsString>
+    where
+        E: Enviroment,
+    {
+        token::bytes_to_os(self.render_bytes(env)?)
+    }
+
+    pub fn render_to_writer<W, E>(&self, out: &mut W, env: &mut E) -> error::Result<()>
+    where
+        W: io::Write,
+        E: Enviroment,
+    {
+        let mut adapter = IoAdapter {
+            inner: out,
+            error: None,
+        };
+        let res = self.render_into(&mut adapter, env);
+        match adapter.error {
+            Some(e) => Err(e.into()),
+            None => res,
+        }
+    }
+}
+
+/// Forwards to an `io::Write`, keeping hold of the I/O error that `fmt::Write`
+/// has no room for.
+struct IoAdapter<'w, W> {
+    inner: &'w mut W,
+    error: Option<io::Error>,
+}
+
+impl<W: io::Write> fmt::Write for IoAdapter<'_, W> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.inner.write_all(s.as_bytes()).map_err(|e| {
+            self.error = Some(e);
+            fmt::Error
+        })
+    }
+}
@@ -1,4 +1,6 @@
-use crate::env::FakeEnv;
+use crate::env::{Enviroment, FakeEnv};
+use crate::options::Options;
+use crate::template::Template;
 
 use super::*;
 
@@ -82,282 +84,1192 @@ fn test_expandvars_get_default() {
     );
 }
 
-// // @patch.dict(env, {"ALTERNATE": "Alternate", "EMPTY": ""}, clear=True)
-// // def test_expandvars_get_default():
-// //     importlib.reload(expandvars)
-// //
-// //
-// // @patch.dict(env, {"EMPTY": ""}, clear=True)
-// // def test_expandvars_update_default():
-// //     importlib.reload(expandvars)
-// //
-// assert_eq!(expand_with(&mut env, "${FOO:=}").unwrap(), ""
-// assert_eq!(expand_with(&mut env, "${FOO=}").unwrap(), ""
-// assert_eq!(expand_with(&mut env, "${EMPTY:=}").unwrap(), ""
-// //
-// //     del env["FOO"]
-// //     del env["EMPTY"]
-// //
-// assert_eq!(expand_with(&mut env, "${FOO:=default}").unwrap(), "default"
-// assert_eq!(expand_with(&mut env, "${FOO=default}").unwrap(), "default"
-// assert_eq!(expand_with(&mut env, "${EMPTY:=default}").unwrap(), "default"
-// //     assert env.get("FOO").unwrap(), "default"
-// assert_eq!(expand_with(&mut env, "${FOO:=ignoreme}").unwrap(), "default"
-// assert_eq!(expand_with(&mut env, "${EMPTY:=ignoreme}").unwrap(), "default"
-// assert_eq!(expand_with(&mut env, "${FOO=ignoreme}:bar").unwrap(), "default:bar"
-// //
-// //
-// // @patch.dict(env, {"FOO": "bar", "BUZ": "bar", "EMPTY": ""}, clear=True)
-// // def test_expandvars_substitute():
-// //     importlib.reload(expandvars)
-// //
-// assert_eq!(expand_with(&mut env, "${FOO:+foo}").unwrap(), "foo"
-// assert_eq!(expand_with(&mut env, "${FOO+foo}").unwrap(), "foo"
-// assert_eq!(expand_with(&mut env, "${BAR:+foo}").unwrap(), ""
-// assert_eq!(expand_with(&mut env, "${BAR+foo}").unwrap(), ""
-// assert_eq!(expand_with(&mut env, "${EMPTY:+foo}").unwrap(), ""
-// assert_eq!(expand_with(&mut env, "${BAR:+}").unwrap(), ""
-// assert_eq!(expand_with(&mut env, "${BAR+}").unwrap(), ""
-// assert_eq!(expand_with(&mut env, "${BUZ:+foo}").unwrap(), "foo"
-// assert_eq!(expand_with(&mut env, "${BUZ+foo}:bar").unwrap(), "foo:bar"
-// assert_eq!(expand_with(&mut env, "${FOO:+${FOO};}").unwrap(), "bar;"
-// assert_eq!(expand_with(&mut env, "${BAR:+${BAR};}").unwrap(), ""
-// assert_eq!(expand_with(&mut env, "${BAR:+${EMPTY};}").unwrap(), ""
-// assert_eq!(expand_with(&mut env, "${FOO:+\\$foo}-\\$foo").unwrap(), "$foo-$foo"
-// //
-// //
-// // @patch.dict(env, {"FOO": "damnbigfoobar"}, clear=True)
-// // def test_offset():
-// //     importlib.reload(expandvars)
-// //
-// assert_eq!(expand_with(&mut env, "${FOO:3}").unwrap(), "nbigfoobar"
-// assert_eq!(expand_with(&mut env, "${FOO: 4 }").unwrap(), "bigfoobar"
-// assert_eq!(expand_with(&mut env, "${FOO:30}").unwrap(), ""
-// assert_eq!(expand_with(&mut env, "${FOO:0}").unwrap(), "damnbigfoobar"
-// assert_eq!(expand_with(&mut env, "${FOO:-3}:bar").unwrap(), "damnbigfoobar:bar"
-// //
-// //
-// // @patch.dict(env, {"FOO": "damnbigfoobar"}, clear=True)
-// // def test_offset_length():
-// //     importlib.reload(expandvars)
-// //
-// assert_eq!(expand_with(&mut env, "${FOO:4:3}").unwrap(), "big"
-// assert_eq!(expand_with(&mut env, "${FOO: 7:6 }").unwrap(), "foobar"
-// assert_eq!(expand_with(&mut env, "${FOO:7: 100 }").unwrap(), "foobar"
-// assert_eq!(expand_with(&mut env, "${FOO:0:100}").unwrap(), "damnbigfoobar"
-// assert_eq!(expand_with(&mut env, "${FOO:70:10}").unwrap(), ""
-// assert_eq!(expand_with(&mut env, "${FOO:1:0}").unwrap(), ""
-// assert_eq!(expand_with(&mut env, "${FOO:0:}").unwrap(), ""
-// assert_eq!(expand_with(&mut env, "${FOO::}").unwrap(), ""
-// assert_eq!(expand_with(&mut env, "${FOO::5}").unwrap(), "damnb"
-// assert_eq!(expand_with(&mut env, "${FOO:-3:1}:bar").unwrap(), "damnbigfoobar:bar"
-// //
-// //
-// // @patch.dict(env, {"FOO": "X", "X": "foo"}, clear=True)
-// // def test_expandvars_indirection():
-// //     importlib.reload(expandvars)
-// //
-// assert_eq!(expand_with(&mut env, "${!FOO}:${FOO}").unwrap(), "foo:X"
-// assert_eq!(expand_with(&mut env, "${!FOO-default}").unwrap(), "foo"
-// assert_eq!(expand_with(&mut env, "${!BAR-default}").unwrap(), "default"
-// assert_eq!(expand_with(&mut env, "${!X-default}").unwrap(), "default"
-// //
-// //
-// // @patch.dict(env, {"FOO": "foo", "BAR": "bar"}, clear=True)
-// // def test_escape():
-// //     importlib.reload(expandvars)
-// //
-// assert_eq!(expand_with(&mut env, "\\$FOO\\$BAR").unwrap(), "$FOO$BAR"
-// assert_eq!(expand_with(&mut env, "\\\\$FOO").unwrap(), "\\foo"
-// assert_eq!(expand_with(&mut env, "$FOO\\$BAR").unwrap(), "foo$BAR"
-// assert_eq!(expand_with(&mut env, "\\$FOO$BAR").unwrap(), "$FOObar"
-// assert_eq!(expand_with(&mut env, "$FOO" "\\" "\\" "\\" "$BAR") == ("foo" "\\" "$BAR")
-// assert_eq!(expand_with(&mut env, "$FOO\\$").unwrap(), "foo$"
-// assert_eq!(expand_with(&mut env, "$\\FOO").unwrap(), "$\\FOO"
-// assert_eq!(expand_with(&mut env, "$\\$FOO").unwrap(), "$$FOO"
-// assert_eq!(expand_with(&mut env, "\\$FOO").unwrap(), "$FOO"
-// //     assert (
-// //         expandvars.expandvars("D:\\\\some\\windows\\path")
-// //         == "D:\\\\some\\windows\\path"
-// //     )
-// //
-// //
-// // @patch.dict(env, {}, clear=True)
-// // def test_corner_cases():
-// //     importlib.reload(expandvars)
-// //
-// assert_eq!(expand_with(&mut env, "${FOO:-{}}{}{}{}{{}}").unwrap(), "{}{}{}{}{{}}"
-// assert_eq!(expand_with(&mut env, "${FOO-{}}{}{}{}{{}}").unwrap(), "{}{}{}{}{{}}"
-// //
-// //
-// // @patch.dict(env, {}, clear=True)
-// // def test_strict_parsing():
-// //     importlib.reload(expandvars)
-// //
-// //     with pytest.raises(
-// //         expandvars.ExpandvarsException, match="FOO: parameter null or not set"
-// //     ) as e:
-// //         expandvars.expandvars("${FOO:?}")
-// //     assert isinstance(e.value, expandvars.ParameterNullOrNotSet)
-// //
-// //     with pytest.raises(
-// //         expandvars.ExpandvarsException, match="FOO: parameter null or not set"
-// //     ) as e:
-// //         expandvars.expandvars("${FOO?}")
-// //     assert isinstance(e.value, expandvars.ParameterNullOrNotSet)
-// //
-// //     with pytest.raises(expandvars.ExpandvarsException, match="FOO: custom error") as e:
-// //         expandvars.expandvars("${FOO:?custom error}")
-// //     assert isinstance(e.value, expandvars.ParameterNullOrNotSet)
-// //
-// //     with pytest.raises(expandvars.ExpandvarsException, match="FOO: custom error") as e:
-// //         expandvars.expandvars("${FOO?custom error}")
-// //     assert isinstance(e.value, expandvars.ParameterNullOrNotSet)
-// //
-// //     env.update({"FOO": "foo"})
-// //
-// assert_eq!(expand_with(&mut env, "${FOO:?custom err}").unwrap(), "foo"
-// assert_eq!(expand_with(&mut env, "${FOO?custom err}:bar").unwrap(), "foo:bar"
-// //
-// //
-// // @patch.dict(env, {"FOO": "foo"}, clear=True)
-// // def test_missing_escapped_character():
-// //     importlib.reload(expandvars)
-// //
-// //     with pytest.raises(expandvars.ExpandvarsException) as e:
-// //         expandvars.expandvars("$FOO\\")
-// //
-// //     assert str(e.value).unwrap(), "$FOO\\: missing escaped character"
-// //     assert isinstance(e.value, expandvars.MissingExcapedChar)
-// //
-// //
-// // @patch.dict(env, {"FOO": "damnbigfoobar"}, clear=True)
-// // def test_invalid_length_err():
-// //     importlib.reload(expandvars)
-// //
-// //     with pytest.raises(
-// //         expandvars.ExpandvarsException, match="FOO: -3: substring expression < 0"
-// //     ) as e:
-// //         expandvars.expandvars("${FOO:1:-3}")
-// //     assert isinstance(e.value, expandvars.NegativeSubStringExpression)
-// //
-// //
-// // @patch.dict(env, {"FOO": "damnbigfoobar"}, clear=True)
-// // def test_bad_substitution_err():
-// //     importlib.reload(expandvars)
-// //
-// //     with pytest.raises(expandvars.ExpandvarsException) as e:
-// //         expandvars.expandvars("${FOO:}").unwrap(), ""
-// //     assert str(e.value).unwrap(), "${FOO:}: bad substitution"
-// //     assert isinstance(e.value, expandvars.BadSubstitution)
-// //
-// //     with pytest.raises(expandvars.ExpandvarsException) as e:
-// //         expandvars.expandvars("${}").unwrap(), ""
-// //     assert str(e.value).unwrap(), "${}: bad substitution"
-// //     assert isinstance(e.value, expandvars.BadSubstitution)
-// //
-// //
-// // @patch.dict(env, {"FOO": "damnbigfoobar"}, clear=True)
-// // def test_brace_never_closed_err():
-// //     importlib.reload(expandvars)
-// //
-// //     with pytest.raises(expandvars.ExpandvarsException) as e:
-// //         expandvars.expandvars("${FOO:")
-// //     assert str(e.value).unwrap(), "${FOO:: missing '}'"
-// //     assert isinstance(e.value, expandvars.MissingClosingBrace)
-// //
-// //     with pytest.raises(expandvars.ExpandvarsException) as e:
-// //         expandvars.expandvars("${FOO}${BAR")
-// //     assert str(e.value).unwrap(), "${FOO}${BAR: missing '}'"
-// //     assert isinstance(e.value, expandvars.MissingClosingBrace)
-// //
-// //     with pytest.raises(expandvars.ExpandvarsException) as e:
-// //         expandvars.expandvars("${FOO?")
-// //     assert str(e.value).unwrap(), "${FOO?: missing '}'"
-// //     assert isinstance(e.value, expandvars.ExpandvarsException)
-// //
-// //     with pytest.raises(expandvars.ExpandvarsException) as e:
-// //         expandvars.expandvars("${FOO:1")
-// //     assert str(e.value).unwrap(), "${FOO:1: missing '}'"
-// //     assert isinstance(e.value, expandvars.MissingClosingBrace)
-// //
-// //     with pytest.raises(expandvars.ExpandvarsException) as e:
-// //         expandvars.expandvars("${FOO:1:2")
-// //     assert str(e.value).unwrap(), "${FOO:1:2: missing '}'"
-// //     assert isinstance(e.value, expandvars.MissingClosingBrace)
-// //
-// //     with pytest.raises(expandvars.ExpandvarsException) as e:
-// //         expandvars.expandvars("${FOO+")
-// //     assert str(e.value).unwrap(), "${FOO+: missing '}'"
-// //     assert isinstance(e.value, expandvars.MissingClosingBrace)
-// //
-// //     with pytest.raises(expandvars.ExpandvarsException) as e:
-// //         expandvars.expandvars("${FOO-")
-// //     assert str(e.value).unwrap(), "${FOO-: missing '}'"
-// //     assert isinstance(e.value, expandvars.MissingClosingBrace)
-// //
-// //     with pytest.raises(expandvars.ExpandvarsException) as e:
-// //         expandvars.expandvars("${FOO-{{}")
-// //     assert str(e.value).unwrap(), "${FOO-{{}: missing '}'"
-// //     assert isinstance(e.value, expandvars.MissingClosingBrace)
-// //
-// //
-// //
-// // @patch.dict(env, {"FOO": "damnbigfoobar"}, clear=True)
-// // def test_invalid_operand_err():
-// //     importlib.reload(expandvars)
-// //
-// //     oprnds = "@#$%^&*()_'\""
-// //
-// //     for o in oprnds:
-// //         with pytest.raises(expandvars.ExpandvarsException) as e:
-// //             expandvars.expandvars("${{FOO:0:{0}}}".format(o))
-// //         assert str(e.value) == ("FOO: operand expected (error token is {0})").format(
-// //             repr(o)
-// //         )
-// //         assert isinstance(e.value, expandvars.OperandExpected)
-// //
-// //         with pytest.raises(expandvars.ExpandvarsException) as e:
-// //             expandvars.expandvars("${{FOO:{0}:{0}}}".format(o))
-// //         assert str(e.value) == ("FOO: operand expected (error token is {0})").format(
-// //             repr(o)
-// //         )
-// //         assert isinstance(e.value, expandvars.OperandExpected)
-// //
-// //
-// // @pytest.mark.parametrize("var_symbol", ["%", "&", "£", "="])
-// // def test_expand_var_symbol(var_symbol):
-// //     importlib.reload(expandvars)
-// //
-// //     assert (
-// //         expandvars.expand(
-// //             var_symbol + "{FOO}", environ={"FOO": "test"}, var_symbol=var_symbol
-// //         )
-// //         == "test"
-// //     )
-// //     assert (
-// //         expandvars.expand(var_symbol + "FOO", environ={}, var_symbol=var_symbol).unwrap(), ""
-// //     )
-// //     assert (
-// //         expandvars.expand(
-// //             var_symbol + "{FOO:-default_value}", environ={}, var_symbol=var_symbol
-// //         )
-// //         == "default_value"
-// //     )
-// //     with pytest.raises(expandvars.ParameterNullOrNotSet):
-// //         expandvars.expand(var_symbol + "{FOO:?}", environ={}, var_symbol=var_symbol)
-// //
-// //     assert (
-// //         expandvars.expand(
-// //             var_symbol + "{FOO},$HOME", environ={"FOO": "test"}, var_symbol=var_symbol
-// //         )
-// //         == "test,$HOME"
-// //     )
-// //
-// // @patch.dict(env, {"FOO": "bar"}, clear=True)
-// // def test_expandvars_from_file():
-// //     importlib.reload(expandvars)
-// //
-// //     with open("tests/data/foo.txt") as f:
-// //         assert expandvars.expandvars(f).unwrap(), "bar:bar"
-// //
+#[test]
+fn test_expandvars_update_default() {
+    let mut env = FakeEnv::empty().with("EMPTY", "");
+
+    assert_eq!(expand_with(&mut env, "${FOO:=}").unwrap(), "");
+    assert_eq!(expand_with(&mut env, "${FOO=}").unwrap(), "");
+    assert_eq!(expand_with(&mut env, "${EMPTY:=}").unwrap(), "");
+
+    let mut env = FakeEnv::empty();
+
+    assert_eq!(expand_with(&mut env, "${FOO:=default}").unwrap(), "default");
+    assert_eq!(expand_with(&mut env, "${FOO=default}").unwrap(), "default");
+    assert_eq!(
+        expand_with(&mut env, "${EMPTY:=default}").unwrap(),
+        "default"
+    );
+    assert_eq!(env.get("FOO").unwrap(), "default");
+    assert_eq!(
+        expand_with(&mut env, "${FOO:=ignoreme}").unwrap(),
+        "default"
+    );
+    assert_eq!(
+        expand_with(&mut env, "${EMPTY:=ignoreme}").unwrap(),
+        "default"
+    );
+    assert_eq!(
+        expand_with(&mut env, "${FOO=ignoreme}:bar").unwrap(),
+        "default:bar"
+    );
+}
+
+#[test]
+fn test_assign_nul_to_process_env() {
+    let mut env = crate::env::ProcessEnv;
+
+    let err = expand("${EXPANDVARS_TEST_NUL:=a\0b}").unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "EXPANDVARS_TEST_NUL: cannot assign a value containing a NUL byte"
+    );
+    assert!(matches!(
+        err,
+        error::Error::NulInValue {
+            position: error::Span {
+                start: 2,
+                end: 21,
+                ..
+            },
+            ..
+        }
+    ));
+    assert!(env.get("EXPANDVARS_TEST_NUL").is_none());
+
+    // Reading a NUL is fine, as long as nothing is assigned.
+    assert_eq!(
+        expand_with(&mut env, "${EXPANDVARS_TEST_NUL:-a\0b}").unwrap(),
+        "a\0b"
+    );
+}
+
+#[test]
+fn test_expandvars_assign_in_same_string() {
+    let mut env = FakeEnv::empty();
+
+    assert_eq!(
+        expand_with(&mut env, "${FOO:=$BAR-x}:${FOO}:$FOO").unwrap(),
+        "-x:-x:-x"
+    );
+}
+
+#[test]
+fn test_expandvars_null_or_unset() {
+    let mut env = FakeEnv::empty().with("EMPTY", "");
+
+    assert_eq!(expand_with(&mut env, "${EMPTY-default}").unwrap(), "");
+    assert_eq!(
+        expand_with(&mut env, "${EMPTY:-default}").unwrap(),
+        "default"
+    );
+    assert_eq!(
+        expand_with(&mut env, "${UNSET-default}").unwrap(),
+        "default"
+    );
+
+    assert_eq!(expand_with(&mut env, "${EMPTY+alt}").unwrap(), "alt");
+    assert_eq!(expand_with(&mut env, "${EMPTY:+alt}").unwrap(), "");
+    assert_eq!(expand_with(&mut env, "${UNSET+alt}").unwrap(), "");
+
+    assert_eq!(expand_with(&mut env, "${EMPTY?}").unwrap(), "");
+    assert!(expand_with(&mut env, "${EMPTY:?}").is_err());
+    assert!(expand_with(&mut env, "${UNSET?}").is_err());
+
+    assert_eq!(expand_with(&mut env, "${EMPTY=default}").unwrap(), "");
+    assert_eq!(env.get("EMPTY").unwrap(), "");
+    assert_eq!(
+        expand_with(&mut env, "${EMPTY:=default}").unwrap(),
+        "default"
+    );
+    assert_eq!(env.get("EMPTY").unwrap(), "default");
+}
+
+#[test]
+fn test_expandvars_substitute() {
+    let mut env = FakeEnv::empty()
+        .with("FOO", "bar")
+        .with("BUZ", "bar")
+        .with("EMPTY", "");
+
+    assert_eq!(expand_with(&mut env, "${FOO:+foo}").unwrap(), "foo");
+    assert_eq!(expand_with(&mut env, "${FOO+foo}").unwrap(), "foo");
+    assert_eq!(expand_with(&mut env, "${BAR:+foo}").unwrap(), "");
+    assert_eq!(expand_with(&mut env, "${BAR+foo}").unwrap(), "");
+    assert_eq!(expand_with(&mut env, "${EMPTY:+foo}").unwrap(), "");
+    assert_eq!(expand_with(&mut env, "${BAR:+}").unwrap(), "");
+    assert_eq!(expand_with(&mut env, "${BAR+}").unwrap(), "");
+    assert_eq!(expand_with(&mut env, "${BUZ:+foo}").unwrap(), "foo");
+    assert_eq!(expand_with(&mut env, "${BUZ+foo}:bar").unwrap(), "foo:bar");
+    assert_eq!(expand_with(&mut env, "${FOO:+${FOO};}").unwrap(), "bar;");
+    assert_eq!(expand_with(&mut env, "${BAR:+${BAR};}").unwrap(), "");
+    assert_eq!(expand_with(&mut env, "${BAR:+${EMPTY};}").unwrap(), "");
+}
+
+#[test]
+fn test_offset() {
+    let mut env = FakeEnv::empty().with("FOO", "damnbigfoobar");
+
+    assert_eq!(expand_with(&mut env, "${FOO:3}").unwrap(), "nbigfoobar");
+    assert_eq!(expand_with(&mut env, "${FOO: 4 }").unwrap(), "bigfoobar");
+    assert_eq!(expand_with(&mut env, "${FOO:30}").unwrap(), "");
+    assert_eq!(expand_with(&mut env, "${FOO:0}").unwrap(), "damnbigfoobar");
+    assert_eq!(
+        expand_with(&mut env, "${FOO:-3}:bar").unwrap(),
+        "damnbigfoobar:bar"
+    );
+    assert_eq!(expand_with(&mut env, "${FOO: -3}").unwrap(), "bar");
+    assert_eq!(expand_with(&mut env, "${FOO: -30}").unwrap(), "");
+}
+
+#[test]
+fn test_offset_length() {
+    let mut env = FakeEnv::empty().with("FOO", "damnbigfoobar");
+
+    assert_eq!(expand_with(&mut env, "${FOO:4:3}").unwrap(), "big");
+    assert_eq!(expand_with(&mut env, "${FOO: 7:6 }").unwrap(), "foobar");
+    assert_eq!(expand_with(&mut env, "${FOO:7: 100 }").unwrap(), "foobar");
+    assert_eq!(
+        expand_with(&mut env, "${FOO:0:100}").unwrap(),
+        "damnbigfoobar"
+    );
+    assert_eq!(expand_with(&mut env, "${FOO:70:10}").unwrap(), "");
+    assert_eq!(expand_with(&mut env, "${FOO:1:0}").unwrap(), "");
+    assert_eq!(expand_with(&mut env, "${FOO:0:}").unwrap(), "");
+    assert_eq!(expand_with(&mut env, "${FOO::}").unwrap(), "");
+    assert_eq!(expand_with(&mut env, "${FOO::5}").unwrap(), "damnb");
+    assert_eq!(
+        expand_with(&mut env, "${FOO:-3:1}:bar").unwrap(),
+        "damnbigfoobar:bar"
+    );
+}
+
+#[test]
+fn test_offset_multibyte() {
+    let mut env = FakeEnv::empty().with("FOO", "£€abc");
+
+    assert_eq!(expand_with(&mut env, "${FOO:1}").unwrap(), "€abc");
+    assert_eq!(expand_with(&mut env, "${FOO:1:2}").unwrap(), "€a");
+    assert_eq!(expand_with(&mut env, "${FOO: -4:1}").unwrap(), "€");
+}
+
+#[test]
+fn test_expandvars_indirection() {
+    let mut env = FakeEnv::empty().with("FOO", "X").with("X", "foo");
+
+    assert_eq!(expand_with(&mut env, "${!FOO}:${FOO}").unwrap(), "foo:X");
+    assert_eq!(expand_with(&mut env, "${!FOO-default}").unwrap(), "foo");
+    assert_eq!(expand_with(&mut env, "${!BAR-default}").unwrap(), "default");
+    assert_eq!(expand_with(&mut env, "${!X-default}").unwrap(), "default");
+    assert_eq!(expand_with(&mut env, "${!FOO:1}").unwrap(), "oo");
+
+    assert_eq!(expand_with(&mut env, "${!BAR}").unwrap(), "");
+    assert_eq!(expand_with(&mut env, "${!X=assigned}").unwrap(), "assigned");
+    assert_eq!(env.get("foo").unwrap(), "assigned");
+}
+
+#[test]
+fn test_escape() {
+    let mut env = FakeEnv::empty().with("FOO", "foo").with("BAR", "bar");
+
+    assert_eq!(expand_with(&mut env, "\\$FOO\\$BAR").unwrap(), "$FOO$BAR");
+    assert_eq!(expand_with(&mut env, "\\\\$FOO").unwrap(), "\\foo");
+    assert_eq!(expand_with(&mut env, "$FOO\\$BAR").unwrap(), "foo$BAR");
+    assert_eq!(expand_with(&mut env, "\\$FOO$BAR").unwrap(), "$FOObar");
+    assert_eq!(
+        expand_with(&mut env, "$FOO\\\\\\$BAR").unwrap(),
+        "foo\\$BAR"
+    );
+    assert_eq!(expand_with(&mut env, "$FOO\\$").unwrap(), "foo$");
+    assert_eq!(expand_with(&mut env, "$\\FOO").unwrap(), "$\\FOO");
+    assert_eq!(expand_with(&mut env, "$\\$FOO").unwrap(), "$$FOO");
+    assert_eq!(expand_with(&mut env, "\\$FOO").unwrap(), "$FOO");
+    assert_eq!(
+        expand_with(&mut env, "D:\\\\some\\windows\\path").unwrap(),
+        "D:\\\\some\\windows\\path"
+    );
+    assert_eq!(
+        expand_with(&mut env, "${FOO:+\\$foo}-\\$foo").unwrap(),
+        "$foo-$foo"
+    );
+}
+
+#[test]
+fn test_corner_cases() {
+    let mut env = FakeEnv::empty();
+
+    assert_eq!(
+        expand_with(&mut env, "${FOO:-{}}{}{}{}{{}}").unwrap(),
+        "{}{}{}{}{{}}"
+    );
+    assert_eq!(
+        expand_with(&mut env, "${FOO-{}}{}{}{}{{}}").unwrap(),
+        "{}{}{}{}{{}}"
+    );
+}
+
+#[test]
+fn test_strict_parsing() {
+    let mut env = FakeEnv::empty();
+
+    let err = expand_with(&mut env, "${FOO:?}").unwrap_err();
+    assert_eq!(err.to_string(), "FOO: parameter null or not set");
+    assert!(matches!(err, error::Error::ParameterNullOrNotSet { .. }));
+
+    let err = expand_with(&mut env, "${FOO?}").unwrap_err();
+    assert_eq!(err.to_string(), "FOO: parameter null or not set");
+    assert!(matches!(err, error::Error::ParameterNullOrNotSet { .. }));
+
+    let err = expand_with(&mut env, "${FOO:?custom error}").unwrap_err();
+    assert_eq!(err.to_string(), "FOO: custom error");
+    assert!(matches!(err, error::Error::ParameterNullOrNotSet { .. }));
+
+    let err = expand_with(&mut env, "${FOO?custom error}").unwrap_err();
+    assert_eq!(err.to_string(), "FOO: custom error");
+    assert!(matches!(err, error::Error::ParameterNullOrNotSet { .. }));
+
+    let mut env = env.with("FOO", "foo");
+
+    assert_eq!(expand_with(&mut env, "${FOO:?custom err}").unwrap(), "foo");
+    assert_eq!(
+        expand_with(&mut env, "${FOO?custom err}:bar").unwrap(),
+        "foo:bar"
+    );
+}
+
+#[test]
+fn test_missing_escapped_character() {
+    let mut env = FakeEnv::empty().with("FOO", "foo");
+
+    let err = expand_with(&mut env, "$FOO\\").unwrap_err();
+    assert_eq!(err.to_string(), "$FOO\\: missing escaped character");
+    assert!(matches!(err, error::Error::MissingEscapedChar { .. }));
+}
+
+#[test]
+fn test_invalid_length_err() {
+    let mut env = FakeEnv::empty().with("FOO", "damnbigfoobar");
+
+    let err = expand_with(&mut env, "${FOO:1:-3}").unwrap_err();
+    assert_eq!(err.to_string(), "FOO: -3: substring expression < 0");
+    assert!(matches!(
+        err,
+        error::Error::NegativeSubStringExpression { .. }
+    ));
+}
+
+#[test]
+fn test_bad_substitution_err() {
+    let mut env = FakeEnv::empty().with("FOO", "damnbigfoobar");
+
+    let err = expand_with(&mut env, "${FOO:}").unwrap_err();
+    assert_eq!(err.to_string(), "${FOO:}: bad substitution");
+    assert!(matches!(err, error::Error::BadSubstitution { .. }));
+
+    let err = expand_with(&mut env, "${}").unwrap_err();
+    assert_eq!(err.to_string(), "${}: bad substitution");
+    assert!(matches!(err, error::Error::BadSubstitution { .. }));
+
+    let err = expand_with(&mut env, "${FOO:1:2:3}").unwrap_err();
+    assert!(matches!(err, error::Error::BadSubstitution { .. }));
+}
+
+#[test]
+fn test_brace_never_closed_err() {
+    let mut env = FakeEnv::empty().with("FOO", "damnbigfoobar");
+
+    for input in [
+        "${FOO:",
+        "${FOO}${BAR",
+        "${FOO?",
+        "${FOO:1",
+        "${FOO:1:2",
+        "${FOO+",
+        "${FOO-",
+        "${FOO-{{}",
+        "${FOO:-${BAR}",
+        "${",
+    ] {
+        let err = expand_with(&mut env, input).unwrap_err();
+        assert_eq!(err.to_string(), format!("{input}: missing '}}'"));
+        assert!(matches!(err, error::Error::MissingClosingBrace { .. }));
+    }
+}
+
+#[test]
+fn test_invalid_operand_err() {
+    let mut env = FakeEnv::empty().with("FOO", "damnbigfoobar");
+
+    for o in "@#$%^&*()_'\"".chars() {
+        let err = expand_with(&mut env, &format!("${{FOO:0:{o}}}")).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("FOO: operand expected (error token is '{o}')")
+        );
+        assert!(matches!(err, error::Error::OperandExpected { .. }));
+
+        let err = expand_with(&mut env, &format!("${{FOO:{o}:{o}}}")).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("FOO: operand expected (error token is '{o}')")
+        );
+        assert!(matches!(err, error::Error::OperandExpected { .. }));
+    }
+}
+
+#[test]
+fn test_expand_var_symbol() {
+    for var_symbol in ['%', '&', '£', '='] {
+        let options = Options::new().sigil(var_symbol);
+
+        let mut env = FakeEnv::empty().with("FOO", "test");
+        assert_eq!(
+            options
+                .expand_with(&mut env, &format!("{var_symbol}{{FOO}}"))
+                .unwrap(),
+            "test"
+        );
+        assert_eq!(
+            options
+                .expand_with(&mut env, &format!("{var_symbol}{{FOO}},$HOME"))
+                .unwrap(),
+            "test,$HOME"
+        );
+
+        let mut env = FakeEnv::empty();
+        assert_eq!(
+            options
+                .expand_with(&mut env, &format!("{var_symbol}FOO"))
+                .unwrap(),
+            ""
+        );
+        assert_eq!(
+            options
+                .expand_with(&mut env, &format!("{var_symbol}{{FOO:-default_value}}"))
+                .unwrap(),
+            "default_value"
+        );
+        assert!(matches!(
+            options.expand_with(&mut env, &format!("{var_symbol}{{FOO:?}}")),
+            Err(error::Error::ParameterNullOrNotSet { .. })
+        ));
+        assert_eq!(
+            options
+                .expand_with(&mut env, &format!("\\{var_symbol}FOO"))
+                .unwrap(),
+            format!("{var_symbol}FOO")
+        );
+    }
+}
+
+#[test]
+fn test_expandvars_from_file() {
+    let mut env = FakeEnv::empty().with("FOO", "bar");
+
+    assert_eq!(
+        expand_file_with(&mut env, "tests/data/foo.txt").unwrap(),
+        "bar:bar"
+    );
+
+    let err = expand_file_with(&mut env, "tests/data/missing.txt").unwrap_err();
+    assert!(
+        matches!(&err, error::Error::Io(e) if e.kind() == std::io::ErrorKind::NotFound)
+    );
+    assert_eq!(
+        err,
+        expand_file_with(&mut env, "tests/data/missing.txt").unwrap_err()
+    );
+    assert_ne!(err, expand_reader_with(&mut env, &b"\xff"[..]).unwrap_err());
+}
+
+#[test]
+fn test_expandvars_from_reader() {
+    let mut env = FakeEnv::empty().with("FOO", "bar");
+
+    assert_eq!(
+        expand_reader_with(&mut env, "$FOO:${FOO}".as_bytes()).unwrap(),
+        "bar:bar"
+    );
+
+    let err = expand_reader_with(&mut env, &b"\xff$FOO"[..]).unwrap_err();
+    assert!(matches!(err, error::Error::Io(_)));
+}
+
+#[test]
+fn test_expand_into() {
+    let mut env = FakeEnv::empty().with("FOO", "damnbigfoobar");
+
+    let mut out = String::from("> ");
+    expand_into(&mut out, &mut env, "${FOO:4:3} ${BAR:-$FOO}").unwrap();
+    assert_eq!(out, "> big damnbigfoobar");
+
+    let mut out = String::new();
+    let err = expand_into(&mut out, &mut env, "$FOO ${BAR?}").unwrap_err();
+    assert!(matches!(err, error::Error::ParameterNullOrNotSet { .. }));
+    assert_eq!(out, "damnbigfoobar ");
+}
+
+#[test]
+fn test_expand_to_writer() {
+    let mut env = FakeEnv::empty().with("FOO", "bar");
+
+    let mut out = Vec::new();
+    expand_to_writer(&mut out, &mut env, "$FOO:${FOO}").unwrap();
+    assert_eq!(out, b"bar:bar");
+
+    let mut out = [0; 4];
+    let err = expand_to_writer(&mut &mut out[..], &mut env, "$FOO:${FOO}").unwrap_err();
+    assert!(matches!(err, error::Error::Io(_)));
+}
+
+#[test]
+fn test_template() {
+    let template = Template::new("${FOO:-foo}:${BAR:=bar}").unwrap();
+
+    let mut env = FakeEnv::empty();
+    assert_eq!(template.render(&mut env).unwrap(), "foo:bar");
+    assert_eq!(env.get("BAR").unwrap(), "bar");
+
+    let mut env = FakeEnv::empty().with("FOO", "x").with("BAR", "y");
+    assert_eq!(template.render(&mut env).unwrap(), "x:y");
+    assert_eq!(template.render(&mut env).unwrap(), "x:y");
+
+    let mut out = Vec::new();
+    template.render_to_writer(&mut out, &mut env).unwrap();
+    assert_eq!(out, b"x:y");
+
+    let template = Options::new().sigil('%').template("%FOO:$FOO").unwrap();
+    assert_eq!(template.render(&mut env).unwrap(), "x:$FOO");
+
+    let err = Template::new("${FOO").unwrap_err();
+    assert!(matches!(err, error::Error::MissingClosingBrace { .. }));
+}
+
+#[test]
+fn test_parse_ast() {
+    use ast::{Node, NodeKind, Operator, Param};
+
+    let var = |name| Param {
+        name,
+        indirect: false,
+        operator: None,
+    };
+    // Spans on the first line of ASCII templates.
+    let node = |kind, start: usize, end: usize| Node {
+        kind,
+        span: error::Span {
+            start,
+            end,
+            line: 1,
+            column: start + 1,
+        },
+    };
+
+    assert_eq!(
+        parse("$$ \\$FOO ${!BAR:=${BAZ}{}} ${FOO: 1:2}$").unwrap(),
+        vec![
+            node(NodeKind::Pid, 0, 2),
+            node(NodeKind::Text(" "), 2, 3),
+            node(NodeKind::Escape("$"), 3, 5),
+            node(NodeKind::Text("FOO "), 5, 9),
+            node(
+                NodeKind::Param(Param {
+                    name: "BAR",
+                    indirect: true,
+                    operator: Some(Operator::Assign {
+                        colon: true,
+                        word: vec![
+                            node(NodeKind::Param(var("BAZ")), 17, 23),
+                            node(NodeKind::Char('{'), 23, 24),
+                            node(NodeKind::Char('}'), 24, 25),
+                        ],
+                    }),
+                }),
+                9,
+                26
+            ),
+            node(NodeKind::Text(" "), 26, 27),
+            node(
+                NodeKind::Param(Param {
+                    operator: Some(Operator::Substring {
+                        offset: " 1",
+                        length: Some("2"),
+                    }),
+                    ..var("FOO")
+                }),
+                27,
+                38
+            ),
+            node(NodeKind::Char('$'), 38, 39),
+        ]
+    );
+
+    assert_eq!(
+        parse("\\\\$FOO \\\\\\$").unwrap(),
+        vec![
+            node(NodeKind::Escape("\\"), 0, 2),
+            node(NodeKind::Param(var("FOO")), 2, 6),
+            node(NodeKind::Text(" "), 6, 7),
+            node(NodeKind::Escape("\\$"), 7, 11),
+        ]
+    );
+
+    assert_eq!(
+        Options::new().sigil('%').parse("%FOO $BAR").unwrap(),
+        vec![
+            node(NodeKind::Param(var("FOO")), 0, 4),
+            node(NodeKind::Text(" $BAR"), 4, 9),
+        ]
+    );
+
+    let template = Template::from_bytes(b"caf\xe9 $FOO", Options::new()).unwrap();
+    let mut env = FakeEnv::empty();
+    assert_eq!(template.render_bytes(&mut env).unwrap(), b"caf\xe9 ");
+    assert_eq!(
+        template.render(&mut env).unwrap_err(),
+        template.nodes().unwrap_err()
+    );
+    let template = Template::from_bytes(b"caf\xe9", Options::new()).unwrap();
+    assert_eq!(
+        template.render_cow(&mut env).unwrap_err(),
+        template.nodes().unwrap_err()
+    );
+    let template = Template::from_bytes(b"caf\xe9 $FOO", Options::new()).unwrap();
+    assert!(matches!(
+        template.nodes(),
+        Err(error::Error::NotUnicode {
+            position: error::Span {
+                start: 0,
+                end: 5,
+                ..
+            },
+            ..
+        })
+    ));
+
+    assert!(matches!(
+        parse("${FOO"),
+        Err(error::Error::MissingClosingBrace { .. })
+    ));
+}
+
+#[test]
+fn test_variables() {
+    use variables::{Requirement::*, Variable};
+
+    let var = |name, requirement| Variable { name, requirement };
+
+    assert_eq!(
+        variables("$A ${B:-${C:+$D}} ${E?$F} ${!G=x} ${H:1:2} $$").unwrap(),
+        vec![
+            var("A", Required),
+            var("B", Defaulted),
+            var("C", Conditional),
+            var("D", Conditional),
+            var("E", Required),
+            var("F", Conditional),
+            var("G", Required),
+            var("H", Required),
+        ]
+    );
+
+    assert_eq!(
+        variables("${!A} ${B:-${!C:-x}}").unwrap(),
+        vec![
+            var("A", Required),
+            var("B", Defaulted),
+            var("C", Conditional)
+        ]
+    );
+
+    assert_eq!(
+        variables("${A:-$B} ${B-x} ${A}").unwrap(),
+        vec![var("A", Required), var("B", Defaulted)]
+    );
+
+    assert_eq!(variables("no vars here").unwrap(), vec![]);
+}
+
+#[test]
+fn test_expand_bytes() {
+    let mut env = FakeEnv::empty().with("FOO", "foo");
+
+    assert_eq!(
+        expand_bytes_with(&mut env, b"caf\xe9 $FOO ${BAR:-\xff}").unwrap(),
+        b"caf\xe9 foo \xff"
+    );
+    assert_eq!(expand_bytes_with(&mut env, b"\\$FOO").unwrap(), b"$FOO");
+
+    let err = expand_bytes_with(&mut env, b"${FOO:\xff}").unwrap_err();
+    assert!(matches!(
+        err,
+        error::Error::OperandExpected {
+            position: error::Span {
+                start: 6,
+                end: 7,
+                ..
+            },
+            ..
+        }
+    ));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_expand_os() {
+    use std::ffi::{OsStr, OsString};
+    use std::os::unix::ffi::{OsStrExt, OsStringExt};
+
+    let raw = OsString::from_vec(b"/srv/caf\xe9".to_vec());
+    let mut env = FakeEnv::empty().with("DIR", &raw);
+
+    assert_eq!(
+        expand_os_with(&mut env, OsStr::new("$DIR/bin")).unwrap(),
+        OsString::from_vec(b"/srv/caf\xe9/bin".to_vec())
+    );
+    assert_eq!(
+        expand_os_with(&mut env, OsStr::from_bytes(b"\xff${UNSET:=$DIR}")).unwrap(),
+        OsString::from_vec(b"\xff/srv/caf\xe9".to_vec())
+    );
+    assert_eq!(env.get("UNSET").unwrap(), raw);
+
+    let err = expand_with(&mut env, "say $DIR").unwrap_err();
+    assert_eq!(err.to_string(), "DIR: not valid UTF-8");
+    assert!(matches!(
+        err,
+        error::Error::NotUnicode {
+            position: error::Span {
+                start: 5,
+                end: 8,
+                ..
+            },
+            ..
+        }
+    ));
+    assert!(matches!(
+        expand_with(&mut env, "${DIR:1}").unwrap_err(),
+        error::Error::NotUnicode { .. }
+    ));
+
+    let mut refs = FakeEnv::empty().with("REF", OsStr::from_bytes(b"\xff"));
+    let err = expand_with(&mut refs, "${!REF}").unwrap_err();
+    assert!(matches!(
+        err,
+        error::Error::NotUnicode {
+            position: error::Span {
+                start: 3,
+                end: 6,
+                ..
+            },
+            ..
+        }
+    ));
+
+    assert_eq!(
+        Options::new()
+            .lossy(true)
+            .expand_with(&mut env, "$DIR")
+            .unwrap(),
+        "/srv/caf\u{FFFD}"
+    );
+    assert_eq!(
+        Options::new()
+            .lossy(true)
+            .expand_with(&mut env, "${DIR:5}")
+            .unwrap(),
+        "caf\u{FFFD}"
+    );
+
+    // The replacement character counts as the three bytes it takes.
+    let options = Options::new().lossy(true).max_output(raw.len());
+    let err = options.expand_with(&mut env, "$DIR").unwrap_err();
+    assert!(matches!(err, error::Error::OutputTooLarge { .. }));
+    assert_eq!(
+        options
+            .expand_os_with(&mut env, OsStr::new("$DIR"))
+            .unwrap(),
+        raw
+    );
+    let options = options.max_output(raw.len() + 2);
+    assert_eq!(
+        options.expand_with(&mut env, "$DIR").unwrap(),
+        "/srv/caf\u{FFFD}"
+    );
+}
+
+#[test]
+fn test_expand_cow() {
+    use std::borrow::Cow;
+
+    let mut env = FakeEnv::empty().with("FOO", "foo");
+
+    assert!(matches!(
+        expand_cow_with(&mut env, "no vars here").unwrap(),
+        Cow::Borrowed("no vars here")
+    ));
+    assert!(matches!(
+        expand_cow_with(&mut env, "").unwrap(),
+        Cow::Borrowed("")
+    ));
+    assert!(matches!(
+        expand_cow_with(&mut env, "$FOO").unwrap(),
+        Cow::Owned(s) if s == "foo"
+    ));
+    assert!(matches!(
+        expand_cow_with(&mut env, "\\$FOO").unwrap(),
+        Cow::Owned(s) if s == "$FOO"
+    ));
+}
+
+#[test]
+fn test_nounset() {
+    let options = Options::new().nounset(true);
+    let mut env = FakeEnv::empty().with("FOO", "foo").with("EMPTY", "");
+
+    assert_eq!(options.expand_with(&mut env, "$FOO$EMPTY").unwrap(), "foo");
+    assert_eq!(options.expand_with(&mut env, "${BAR:-bar}").unwrap(), "bar");
+    assert_eq!(options.expand_with(&mut env, "${BAR:+bar}").unwrap(), "");
+    assert_eq!(options.expand_with(&mut env, "${BAR-$FOO}").unwrap(), "foo");
+
+    let err = options.expand_with(&mut env, "foo: $BAR").unwrap_err();
+    assert_eq!(err.to_string(), "BAR: unbound variable");
+    assert!(matches!(
+        err,
+        error::Error::UnsetVariable { name, position: error::Span { start: 6, end: 9, .. } } if name == "BAR"
+    ));
+
+    for input in ["${BAR}", "${BAR:1}", "${!BAR}", "${EMPTY:-$BAR}"] {
+        let err = options.expand_with(&mut env, input).unwrap_err();
+        assert!(
+            matches!(&err, error::Error::UnsetVariable { name, .. } if name == "BAR"),
+            "{input}: {err}"
+        );
+    }
+
+    let mut env = env.with("REF", "BAR");
+    let err = options.expand_with(&mut env, "${!REF}").unwrap_err();
+    assert!(matches!(
+        err,
+        error::Error::UnsetVariable { name, position: error::Span { start: 3, end: 6, .. } } if name == "BAR"
+    ));
+
+    assert_eq!(expand_with(&mut env, "foo: $BAR").unwrap(), "foo: ");
+}
+
+#[test]
+fn test_error_position() {
+    use error::Span;
+
+    let mut env = FakeEnv::empty().with("FOO", "damnbigfoobar");
+
+    let mut position =
+        |input: &str| expand_with(&mut env, input).unwrap_err().position();
+
+    assert_eq!(
+        position("foo\nbar ${BAZ"),
+        Some(Span {
+            start: 8,
+            end: 13,
+            line: 2,
+            column: 5,
+        })
+    );
+    assert_eq!(
+        position("a\nb\nc ${FOO:1:2:3}"),
+        Some(Span {
+            start: 6,
+            end: 16,
+            line: 3,
+            column: 3,
+        })
+    );
+    assert_eq!(
+        position("é ${}"),
+        Some(Span {
+            start: 3,
+            end: 6,
+            line: 1,
+            column: 3,
+        })
+    );
+    assert_eq!(
+        position("trailing \\"),
+        Some(Span {
+            start: 9,
+            end: 10,
+            line: 1,
+            column: 10,
+        })
+    );
+    assert_eq!(
+        position("x ${BAR:?no bar}"),
+        Some(Span {
+            start: 4,
+            end: 7,
+            line: 1,
+            column: 5,
+        })
+    );
+    assert_eq!(
+        position("${FOO:0:-3}"),
+        Some(Span {
+            start: 8,
+            end: 10,
+            line: 1,
+            column: 9,
+        })
+    );
+    assert_eq!(
+        position("${FOO: x }"),
+        Some(Span {
+            start: 7,
+            end: 8,
+            line: 1,
+            column: 8,
+        })
+    );
+
+    assert_eq!(
+        position("\n\n  ${BAZ").unwrap().to_string(),
+        "line 3, column 3"
+    );
+}
+
+/// A xorshift generator, so that the fuzz-style tests below are reproducible.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+}
+
+// Defines `fuzz_env`, the same environment the fuzz targets expand against.
+include!("../fuzz/env.rs");
+
+/// Expands `input` every way there is, checking that errors point inside it.
+fn expand_everything(options: &Options, input: &[u8]) {
+    let check = |res: error::Result<()>| {
+        if let Err(err) = res {
+            if let Some(position) = err.position() {
+                assert!(position.start <= position.end && position.end <= input.len());
+            }
+            #[cfg(feature = "diagnostics")]
+            if let Ok(input) = std::str::from_utf8(input) {
+                crate::diagnostic::render(&err, input);
+            }
+        }
+    };
+
+    check(options.expand_bytes_with(&mut fuzz_env(), input).map(drop));
+    if let Ok(input) = std::str::from_utf8(input) {
+        check(options.expand_with(&mut fuzz_env(), input).map(drop));
+        check(options.parse(input).map(drop));
+        check(options.variables(input).map(drop));
+    }
+}
+
+#[test]
+fn test_arbitrary_bytes_never_panic() {
+    let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+    let options = Options::new();
+
+    for _ in 0..5000 {
+        let input: Vec<u8> = (0..rng.below(32)).map(|_| rng.next() as u8).collect();
+        expand_everything(&options, &input);
+    }
+}
+
+#[test]
+fn test_arbitrary_templates_never_panic() {
+    // Random bytes rarely make it past `$`, so these are built from the pieces
+    // the grammar cares about instead.
+    const PIECES: &[&str] = &[
+        "$", "{", "}", ":", "-", "=", "+", "?", "!", "\\", "0", "1", "-2", " ", "FOO",
+        "EMPTY", "REF", "BAD_REF", "UNSET", "é", "%", "\n",
+    ];
+    let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+
+    for sigil in ['$', '%', 'é', '{', '}', ':', '\\', '!'] {
+        for nounset in [false, true] {
+            let options = Options::new().sigil(sigil).nounset(nounset);
+            for _ in 0..1000 {
+                let input: String = (0..rng.below(16))
+                    .map(|_| PIECES[rng.below(PIECES.len())])
+                    .collect();
+                expand_everything(&options, input.as_bytes());
+            }
+        }
+    }
+}
+
+/// Replays inputs the fuzz targets have tripped over, or that are worth
+/// keeping an eye on, from `fuzz/regressions`.
+#[test]
+fn test_fuzz_regressions() {
+    let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/fuzz/regressions");
+    let mut count = 0;
+    for entry in std::fs::read_dir(dir).unwrap() {
+        let input = std::fs::read(entry.unwrap().path()).unwrap();
+        for nounset in [false, true] {
+            expand_everything(&Options::new().nounset(nounset), &input);
+        }
+        count += 1;
+    }
+    assert!(count > 0);
+}
+
+#[test]
+fn test_max_depth() {
+    let mut env = FakeEnv::empty().with("FOO", "foo");
+
+    let nested = |n: usize| "${A:-".repeat(n) + "$FOO" + &"}".repeat(n);
+    assert_eq!(expand_with(&mut env, &nested(64)).unwrap(), "foo");
+
+    let err = expand_with(&mut env, &nested(65)).unwrap_err();
+    assert!(matches!(
+        err,
+        error::Error::TooDeep {
+            max_depth: 64,
+            position: error::Span {
+                start: 320,
+                end: 322,
+                ..
+            },
+            ..
+        }
+    ));
+
+    let options = Options::new().max_depth(2);
+    assert_eq!(options.expand_with(&mut env, "${A:-${B:-x}}").unwrap(), "x");
+    assert_eq!(options.expand_with(&mut env, "${A:-{x}}").unwrap(), "{x}");
+    for input in ["${A:-${B:-${C}}}", "${A:-{{x}}}", "${A:-${B:-{x}}}"] {
+        let err = options.expand_with(&mut env, input).unwrap_err();
+        assert!(
+            matches!(err, error::Error::TooDeep { .. }),
+            "{input}: {err}"
+        );
+    }
+
+    let err = Options::new()
+        .max_depth(0)
+        .expand_with(&mut env, "ok $FOO, not ${FOO}")
+        .unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "ok $FOO, not ${FOO}: nesting deeper than 0"
+    );
+
+    // Deep enough to overflow the stack without a limit.
+    let input = nested(100_000);
+    let err = Options::new().expand_with(&mut env, &input).unwrap_err();
+    assert!(matches!(err, error::Error::TooDeep { .. }));
+}
+
+#[test]
+fn test_max_depth_on_expansion() {
+    let input = b"${A:-${B:-${C:-x}}}";
+    let tokens =
+        crate::parser::parse(input, &crate::parser::Context::default()).unwrap();
+    let mut env = FakeEnv::empty();
+
+    let options = Options::new();
+    let mut out = Vec::new();
+    let ctx = token::Context::new(&options, input);
+    token::expand_all_into(&tokens, &mut env, &mut out, &ctx).unwrap();
+    assert_eq!(out, b"x");
+
+    let options = Options::new().max_depth(2);
+    let ctx = token::Context::new(&options, input);
+    let err =
+        token::expand_all_into(&tokens, &mut env, &mut Vec::new(), &ctx).unwrap_err();
+    assert!(matches!(
+        err,
+        error::Error::TooDeep {
+            max_depth: 2,
+            position: error::Span {
+                start: 12,
+                end: 13,
+                ..
+            },
+            ..
+        }
+    ));
+}
+
+#[test]
+fn test_max_output() {
+    let mut env = FakeEnv::empty().with("BIG", "x".repeat(1000));
+    let options = Options::new().max_output(2500);
+
+    assert_eq!(
+        options.expand_with(&mut env, "$BIG$BIG").unwrap().len(),
+        2000
+    );
+    assert_eq!(
+        options
+            .expand_with(&mut env, "$BIG$BIG${BIG:0:500}")
+            .unwrap()
+            .len(),
+        2500
+    );
+
+    let err = options.expand_with(&mut env, "$BIG$BIG$BIG").unwrap_err();
+    assert_eq!(err.to_string(), "output exceeds 2500 bytes");
+    assert!(matches!(
+        err,
+        error::Error::OutputTooLarge { max_output: 2500 }
+    ));
+
+    // Nothing past the limit reaches the output.
+    let mut out = Vec::new();
+    let err = options
+        .expand_to_writer(&mut out, &mut env, "$BIG$BIG$BIG")
+        .unwrap_err();
+    assert!(matches!(err, error::Error::OutputTooLarge { .. }));
+    assert_eq!(out.len(), 2000);
+
+    let options = Options::new().max_output(3);
+    assert!(options.expand_bytes_with(&mut env, b"abcd").is_err());
+    assert!(options.expand_cow_with(&mut env, "abcd").is_err());
+    assert!(options.expand_with(&mut env, "${UNSET:-abcd}").is_err());
+    assert!(options.expand_with(&mut env, "${UNSET:=abcd}").is_err());
+    assert!(env.get("UNSET").is_none());
+    assert_eq!(
+        options.expand_with(&mut env, "${UNSET:=abc}").unwrap(),
+        "abc"
+    );
+    assert_eq!(
+        options
+            .expand_with(&mut env, "${NEW:=${INNER:=ab}}")
+            .unwrap(),
+        "ab"
+    );
+    assert!(options.expand_with(&mut env, "a${NEW2:=bcd}").is_err());
+    assert!(env.get("NEW2").is_none());
+    let err = options.expand_with(&mut env, "${UNSET2?$BIG}").unwrap_err();
+    assert!(matches!(err, error::Error::OutputTooLarge { .. }));
+    assert!(matches!(
+        options.expand_cow_with(&mut env, "abc").unwrap(),
+        std::borrow::Cow::Borrowed("abc")
+    ));
+
+    let mut env = FakeEnv::empty().with("BIG", "x".repeat(1000));
+    assert_eq!(
+        expand_with(&mut env, &"$BIG".repeat(100)).unwrap().len(),
+        100_000
+    );
+}
+
+#[test]
+fn test_filter_patterns() {
+    use crate::filter::{Filter, Pattern};
+
+    assert!(Pattern::name("HOME").matches("HOME"));
+    assert!(!Pattern::name("HOME").matches("HOMEDIR"));
+    assert!(Pattern::prefix("APP_").matches("APP_NAME"));
+    assert!(!Pattern::prefix("APP_").matches("MY_APP_NAME"));
+
+    let glob = Pattern::glob("AWS_*_KEY*");
+    assert!(glob.matches("AWS_SECRET_ACCESS_KEY"));
+    assert!(glob.matches("AWS__KEY"));
+    assert!(glob.matches("AWS_A_KEY_ID"));
+    assert!(!glob.matches("AWS_REGION"));
+    assert!(Pattern::glob("?_*").matches("A_B"));
+    assert!(!Pattern::glob("?_*").matches("AB_C"));
+    assert!(Pattern::glob("*").matches(""));
+    assert!(Pattern::glob("**A*A").matches("BAAA"));
+    assert!(!Pattern::glob("").matches("A"));
+
+    let filter = Filter::new()
+        .allow(Pattern::prefix("APP_"))
+        .allow(Pattern::name("HOME"))
+        .deny(Pattern::glob("*SECRET*"));
+    assert!(filter.allows("APP_NAME"));
+    assert!(filter.allows("HOME"));
+    assert!(!filter.allows("APP_SECRET"));
+    assert!(!filter.allows("PATH"));
+
+    assert!(Filter::new().allows("PATH"));
+}
+
+#[test]
+fn test_filter_policies() {
+    use crate::filter::{Filter, OnDenied, Pattern};
+
+    let env = || {
+        FakeEnv::empty()
+            .with("APP_NAME", "demo")
+            .with("SECRET", "hunter2")
+            .with("REF", "SECRET")
+    };
+    let filter = Filter::new().deny(Pattern::name("SECRET"));
+    let input = "$APP_NAME $SECRET ${SECRET:-none} ${!REF} ${APP_NAME:+${SECRET:1}}";
+
+    let options = Options::new().filter(filter.clone());
+    assert_eq!(
+        options.expand_with(&mut env(), input).unwrap(),
+        "demo  none  "
+    );
+
+    let mut env_ = env();
+    assert_eq!(
+        options.expand_with(&mut env_, "${SECRET:=leaked}").unwrap(),
+        "leaked"
+    );
+    assert_eq!(env_.get("SECRET").unwrap(), "hunter2");
+
+    let options = Options::new().filter(filter.clone().on_denied(OnDenied::Literal));
+    assert_eq!(
+        options.expand_with(&mut env(), input).unwrap(),
+        "demo $SECRET ${SECRET:-none} ${!REF} ${SECRET:1}"
+    );
+    assert_eq!(
+        Options::new()
+            .sigil('%')
+            .filter(filter.clone().on_denied(OnDenied::Literal))
+            .expand_with(&mut env(), "%{SECRET:-{a}}.%SECRET.%APP_NAME")
+            .unwrap(),
+        "%{SECRET:-{a}}.%SECRET.demo"
+    );
+
+    let options = Options::new().filter(filter.on_denied(OnDenied::Error));
+    assert_eq!(
+        options.expand_with(&mut env(), "$APP_NAME").unwrap(),
+        "demo"
+    );
+    for input in [
+        "$SECRET",
+        "${SECRET:-none}",
+        "${!REF}",
+        "x ${APP_NAME:+$SECRET}",
+    ] {
+        let err = options.expand_with(&mut env(), input).unwrap_err();
+        assert_eq!(err.to_string(), "SECRET: not allowed", "{input}");
+    }
+    let err = options.expand_with(&mut env(), "x $SECRET").unwrap_err();
+    assert!(matches!(
+        err,
+        error::Error::DeniedVariable {
+            position: error::Span {
+                start: 3,
+                end: 9,
+                ..
+            },
+            ..
+        }
+    ));
+
+    let options = Options::new().filter(Filter::new().allow(Pattern::prefix("APP_")));
+    assert_eq!(
+        options.expand_with(&mut env(), "$APP_NAME:$REF").unwrap(),
+        "demo:"
+    );
+}
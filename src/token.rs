@@ -1,51 +1,624 @@
-use crate::{env::Enviroment, error::Error};
+use std::{
+    borrow::Cow,
+    cell::Cell,
+    env::VarError,
+    ffi::{OsStr, OsString},
+    fmt,
+};
 
-fn to_string(bytes: &[u8]) -> Result<String, Error> {
-    String::from_utf8(bytes.into()).map_err(Error::from)
+use crate::{
+    env::Enviroment,
+    error::{Error, Span},
+    filter::{Filter, OnDenied},
+    options::Options,
+    parser::is_variable_name,
+};
+
+/// Decodes an environment value, replacing invalid UTF-8 only if `lossy`.
+pub(crate) fn decode(value: &OsStr, lossy: bool) -> Result<Cow<'_, str>, Error> {
+    if lossy {
+        return Ok(value.to_string_lossy());
+    }
+    match value.to_str() {
+        Some(s) => Ok(Cow::Borrowed(s)),
+        None => Err(VarError::NotUnicode(value.to_os_string()).into()),
+    }
 }
 
-// TODO use OsString?
-fn get_value<E>(name: &[u8], env: &E) -> Result<Option<String>, Error>
-where
-    E: Enviroment,
-{
-    let key = to_string(name)?;
-    let var = if let Some(val) = env.get(&key) {
-        if val.is_empty() {
-            None
-        } else {
-            Some(val.to_string_lossy().to_string())
+/// The raw bytes of an `OsStr`, which outside of Unix must be valid UTF-8.
+pub(crate) fn os_to_bytes(value: &OsStr, lossy: bool) -> Result<Cow<'_, [u8]>, Error> {
+    #[cfg(unix)]
+    {
+        let _ = lossy;
+        Ok(Cow::Borrowed(std::os::unix::ffi::OsStrExt::as_bytes(value)))
+    }
+    #[cfg(not(unix))]
+    {
+        Ok(str_to_bytes(decode(value, lossy)?))
+    }
+}
+
+fn str_to_bytes(s: Cow<'_, str>) -> Cow<'_, [u8]> {
+    match s {
+        Cow::Borrowed(s) => Cow::Borrowed(s.as_bytes()),
+        Cow::Owned(s) => Cow::Owned(s.into_bytes()),
+    }
+}
+
+pub(crate) fn bytes_to_os(bytes: Vec<u8>) -> Result<OsString, Error> {
+    #[cfg(unix)]
+    {
+        Ok(std::os::unix::ffi::OsStringExt::from_vec(bytes))
+    }
+    #[cfg(not(unix))]
+    {
+        Ok(String::from_utf8(bytes)?.into())
+    }
+}
+
+/// Where expanded output goes, either text or raw bytes.
+pub(crate) trait Output {
+    fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), Error>;
+    fn write_str(&mut self, s: &str) -> Result<(), Error>;
+
+    /// What writing `value` comes down to in this sink, which depends on
+    /// whether it has to be decoded.
+    fn encode_os<'v>(
+        &self,
+        value: &'v OsStr,
+        lossy: bool,
+    ) -> Result<Cow<'v, [u8]>, Error>;
+
+    fn write_os(&mut self, value: &OsStr, lossy: bool) -> Result<(), Error> {
+        let bytes = self.encode_os(value, lossy)?;
+        self.write_bytes(&bytes)
+    }
+}
+
+impl Output for Vec<u8> {
+    fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        self.extend_from_slice(bytes);
+        Ok(())
+    }
+
+    fn write_str(&mut self, s: &str) -> Result<(), Error> {
+        self.write_bytes(s.as_bytes())
+    }
+
+    fn encode_os<'v>(
+        &self,
+        value: &'v OsStr,
+        lossy: bool,
+    ) -> Result<Cow<'v, [u8]>, Error> {
+        os_to_bytes(value, lossy)
+    }
+}
+
+/// A text sink, which can only take bytes that are valid UTF-8.
+pub(crate) struct Text<'w, W>(pub(crate) &'w mut W);
+
+impl<W: fmt::Write> Output for Text<'_, W> {
+    fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        self.write_str(to_str(bytes)?)
+    }
+
+    fn write_str(&mut self, s: &str) -> Result<(), Error> {
+        self.0.write_str(s).map_err(Error::from)
+    }
+
+    fn encode_os<'v>(
+        &self,
+        value: &'v OsStr,
+        lossy: bool,
+    ) -> Result<Cow<'v, [u8]>, Error> {
+        Ok(str_to_bytes(decode(value, lossy)?))
+    }
+
+    fn write_os(&mut self, value: &OsStr, lossy: bool) -> Result<(), Error> {
+        self.write_str(&decode(value, lossy)?)
+    }
+}
+
+/// Fails once writing more would take the expansion past `max_output` bytes.
+/// Every sink of one expansion counts against the same total, kept in its
+/// [`Context`].
+pub(crate) struct Limited<'o, O> {
+    inner: &'o mut O,
+    max_output: Option<usize>,
+    written: &'o Cell<usize>,
+}
+
+impl<'o, O> Limited<'o, O> {
+    pub(crate) fn new(inner: &'o mut O, ctx: &'o Context) -> Self {
+        Self {
+            inner,
+            max_output: ctx.options.max_output,
+            written: &ctx.written,
+        }
+    }
+
+    fn reserve(&mut self, len: usize) -> Result<(), Error> {
+        let written = self.written.get().saturating_add(len);
+        match self.max_output {
+            Some(max_output) if written > max_output => {
+                Err(Error::OutputTooLarge { max_output })
+            }
+            _ => {
+                self.written.set(written);
+                Ok(())
+            }
+        }
+    }
+}
+
+impl<O: Output> Output for Limited<'_, O> {
+    fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        self.reserve(bytes.len())?;
+        self.inner.write_bytes(bytes)
+    }
+
+    fn write_str(&mut self, s: &str) -> Result<(), Error> {
+        self.reserve(s.len())?;
+        self.inner.write_str(s)
+    }
+
+    fn encode_os<'v>(
+        &self,
+        value: &'v OsStr,
+        lossy: bool,
+    ) -> Result<Cow<'v, [u8]>, Error> {
+        self.inner.encode_os(value, lossy)
+    }
+}
+
+/// A name as it appears in error messages, which indirect expansion may have
+/// taken from a value that is not valid UTF-8.
+fn to_string(bytes: &[u8]) -> String {
+    String::from_utf8_lossy(bytes).into_owned()
+}
+
+fn to_str(bytes: &[u8]) -> Result<&str, Error> {
+    std::str::from_utf8(bytes).map_err(Error::from)
+}
+
+/// Formats the ID of the current process into `buf`, which fits any `u32`.
+fn format_pid(buf: &mut [u8; 10]) -> &[u8] {
+    let mut n = std::process::id();
+    let mut start = buf.len();
+    loop {
+        start -= 1;
+        buf[start] = b'0' + (n % 10) as u8;
+        n /= 10;
+        if n == 0 {
+            return &buf[start..];
         }
+    }
+}
+
+/// Indirect expansion can produce names that are not valid to assign to.
+fn is_valid_name(name: &[u8]) -> bool {
+    !name.is_empty() && name.iter().copied().all(is_variable_name)
+}
+
+/// With the colon form of an operator, a set but empty value counts as unset.
+fn check_null(value: Option<OsString>, colon: bool) -> Option<OsString> {
+    if colon {
+        value.filter(|v| !v.is_empty())
     } else {
-        None
+        value
+    }
+}
+
+/// Parses a substring offset or length, where blank means zero.
+fn parse_operand(name: &[u8], operand: &[u8], ctx: &Context) -> Result<i64, Error> {
+    let expected = |token: &[u8]| Error::OperandExpected {
+        name: to_string(name),
+        token: to_string(token),
+        position: Span::of(ctx.input, token),
     };
+    let Ok(operand) = to_str(operand) else {
+        return Err(expected(operand.trim_ascii()));
+    };
+    let operand = operand.trim();
+    if operand.is_empty() {
+        return Ok(0);
+    }
 
-    Ok(var)
+    operand.parse().map_err(|_| expected(operand.as_bytes()))
 }
 
-#[derive(Debug, PartialEq, Eq)]
+/// Extracts `length` characters starting at `offset`, where a negative offset
+/// counts back from the end of the value.
+fn substring<'v>(
+    name: &[u8],
+    value: &'v str,
+    offset: &[u8],
+    length: Option<&[u8]>,
+    ctx: &Context,
+) -> Result<&'v str, Error> {
+    let start = parse_operand(name, offset, ctx)?;
+    let length = match length {
+        Some(length) => {
+            let n = parse_operand(name, length, ctx)?;
+            if n < 0 {
+                return Err(Error::NegativeSubStringExpression {
+                    name: to_string(name),
+                    expression: n.to_string(),
+                    position: Span::of(ctx.input, length),
+                });
+            }
+            Some(n as usize)
+        }
+        None => None,
+    };
+
+    let count = value.chars().count() as i64;
+    let start = if start < 0 { count + start } else { start };
+    if start < 0 || start > count {
+        return Ok("");
+    }
+
+    let byte_offset =
+        |n: usize| value.char_indices().nth(n).map_or(value.len(), |(i, _)| i);
+    let start = start as usize;
+    let end = match length {
+        Some(length) => byte_offset(start.saturating_add(length)),
+        None => value.len(),
+    };
+    Ok(&value[byte_offset(start)..end])
+}
+
+/// What expansion needs besides the environment and the output, along with how
+/// deeply nested it currently is and how much it has written.
+pub(crate) struct Context<'c> {
+    pub(crate) options: &'c Options,
+    /// The whole template, which the names in tokens point into.
+    pub(crate) input: &'c [u8],
+    depth: Cell<usize>,
+    /// Bytes written so far, counting words expanded on the side.
+    written: Cell<usize>,
+}
+
+impl<'c> Context<'c> {
+    pub(crate) fn new(options: &'c Options, input: &'c [u8]) -> Self {
+        Self {
+            options,
+            input,
+            depth: Cell::new(0),
+            written: Cell::new(0),
+        }
+    }
+
+    /// Gives back what a word expanded on the side took, before it is written
+    /// to the output where it counts again.
+    fn release(&self, len: usize) {
+        self.written.set(self.written.get().saturating_sub(len));
+    }
+
+    /// What happens to variables the filter denies, if there is a filter.
+    fn policy(&self) -> Option<OnDenied> {
+        self.options.filter.as_ref().map(Filter::policy)
+    }
+
+    fn allows(&self, name: &[u8]) -> bool {
+        match &self.options.filter {
+            Some(filter) => {
+                std::str::from_utf8(name).is_ok_and(|name| filter.allows(name))
+            }
+            None => true,
+        }
+    }
+
+    /// Runs `f` to expand the word applied to `name` one level of nesting
+    /// deeper, failing if that goes past the limit.
+    fn nested<T>(
+        &self,
+        name: &[u8],
+        f: impl FnOnce() -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        let depth = self.depth.get() + 1;
+        if depth > self.options.max_depth {
+            return Err(Error::TooDeep {
+                input: String::from_utf8_lossy(self.input).into_owned(),
+                max_depth: self.options.max_depth,
+                position: Span::of(self.input, name),
+            });
+        }
+        self.depth.set(depth);
+        let res = f();
+        self.depth.set(depth - 1);
+        res
+    }
+}
+
+/// A parsed piece of a template. The slices point into the template itself, so
+/// errors can tell where they come from.
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub(crate) enum Token<'a> {
     Const(&'a [u8]),
-    Var(&'a [u8]),
-    Char(char),
-    Pid,
-    VarWithDefault(&'a [u8], Box<Token<'a>>),
+    /// Backslashes as written, followed by the sigil if one of them escapes it.
+    Escape(&'a [u8]),
+    /// A character that is not part of a substitution, as written.
+    Char(&'a [u8], char),
+    /// `$$` or `${$}` as written.
+    Pid(&'a [u8]),
+    Param(Param<'a>),
+}
+
+/// A reference to a variable, where `source` is the whole reference as written.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Param<'a> {
+    pub(crate) source: &'a [u8],
+    pub(crate) name: &'a [u8],
+    pub(crate) indirect: bool,
+    pub(crate) operator: Option<Operator<'a>>,
+}
+
+/// An operator inside `${...}`, along with whether its colon form was used.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Operator<'a> {
+    Default(bool, Vec<Token<'a>>),
+    Assign(bool, Vec<Token<'a>>),
+    Alternate(bool, Vec<Token<'a>>),
+    Error(bool, Vec<Token<'a>>),
+    Substring(&'a [u8], Option<&'a [u8]>),
+}
+
+/// What an escape stands for: half of its backslashes, followed by the sigil if
+/// there was an odd one out.
+pub(crate) fn unescape(escape: &[u8]) -> &[u8] {
+    let n = escape.iter().take_while(|&&c| c == b'\\').count();
+    &escape[n - n / 2..]
+}
+
+pub(crate) fn expand_all_into<E, W>(
+    tokens: &[Token],
+    env: &mut E,
+    out: &mut W,
+    ctx: &Context,
+) -> Result<(), Error>
+where
+    E: Enviroment,
+    W: Output,
+{
+    for tok in tokens {
+        tok.expand_into(env, out, ctx)?;
+    }
+    Ok(())
+}
+
+fn expand_all<E>(tokens: &[Token], env: &mut E, ctx: &Context) -> Result<String, Error>
+where
+    E: Enviroment,
+{
+    let mut res = String::new();
+    let mut out = Text(&mut res);
+    expand_all_into(tokens, env, &mut Limited::new(&mut out, ctx), ctx)?;
+    Ok(res)
+}
+
+fn expand_all_os<E>(
+    tokens: &[Token],
+    env: &mut E,
+    ctx: &Context,
+) -> Result<OsString, Error>
+where
+    E: Enviroment,
+{
+    let mut res = Vec::new();
+    expand_all_into(tokens, env, &mut Limited::new(&mut res, ctx), ctx)?;
+    bytes_to_os(res)
 }
 
 impl Token<'_> {
-    pub(crate) fn expand_with<E>(self, env: &mut E) -> Result<String, Error>
+    /// Writes the expansion straight into `out`, without building a string for
+    /// each token.
+    pub(crate) fn expand_into<E, W>(
+        &self,
+        env: &mut E,
+        out: &mut W,
+        ctx: &Context,
+    ) -> Result<(), Error>
     where
         E: Enviroment,
+        W: Output,
     {
         match self {
-            Token::Const(s) => to_string(s),
-            Token::Char(c) => Ok(c.into()),
-            Token::Pid => Ok(std::process::id().to_string()),
-            Token::Var(name) => get_value(name, env).map(|v| v.unwrap_or_default()),
-            Token::VarWithDefault(name, default) => {
-                let default = default.expand_with(env)?;
-                get_value(name, env).map(|v| v.unwrap_or(default))
+            Token::Const(s) => match out.write_bytes(s) {
+                Err(Error::Utf8Error(_)) => Err(Error::not_unicode(ctx.input, s)),
+                res => res,
+            },
+            Token::Char(s, _) => out.write_bytes(s),
+            Token::Escape(s) => out.write_bytes(unescape(s)),
+            Token::Pid(_) => out.write_bytes(format_pid(&mut [0; 10])),
+            Token::Param(param) => match param.expand_into(env, out, ctx) {
+                Err(Error::DeniedVariable { .. })
+                    if ctx.policy() == Some(OnDenied::Literal) =>
+                {
+                    out.write_bytes(param.source)
+                }
+                res => res,
+            },
+        }
+    }
+}
+
+impl Param<'_> {
+    /// Looks `name` up, unless the filter denies it. Returns `None` if the
+    /// variable is unset, and `Some("")` if it is set but empty.
+    fn lookup<E>(
+        &self,
+        name: &[u8],
+        env: &E,
+        ctx: &Context,
+    ) -> Result<Option<OsString>, Error>
+    where
+        E: Enviroment,
+    {
+        match ctx.policy() {
+            Some(policy) if !ctx.allows(name) => match policy {
+                OnDenied::Empty => Ok(None),
+                OnDenied::Literal | OnDenied::Error => Err(Error::DeniedVariable {
+                    name: to_string(name),
+                    position: Span::of(ctx.input, self.name),
+                }),
+            },
+            _ => match std::str::from_utf8(name) {
+                Ok(name) => Ok(env.get(name)),
+                Err(_) => Err(self.not_unicode(name, ctx)),
+            },
+        }
+    }
+
+    fn not_unicode(&self, name: &[u8], ctx: &Context) -> Error {
+        Error::NotUnicode {
+            name: to_string(name),
+            position: Span::of(ctx.input, self.name),
+        }
+    }
+
+    /// Writes the value of `name`, failing at the reference if it has to be
+    /// decoded and cannot be.
+    fn write_value<W>(
+        &self,
+        name: &[u8],
+        value: &OsStr,
+        out: &mut W,
+        ctx: &Context,
+    ) -> Result<(), Error>
+    where
+        W: Output,
+    {
+        match out.write_os(value, ctx.options.lossy) {
+            Err(Error::VarError(VarError::NotUnicode(_))) => {
+                Err(self.not_unicode(name, ctx))
+            }
+            res => res,
+        }
+    }
+
+    /// Called when `name` turns out to be unset and there is no default to fall
+    /// back on, which is only an error in nounset mode.
+    fn unset(&self, name: &[u8], ctx: &Context) -> Result<(), Error> {
+        if !ctx.options.nounset {
+            return Ok(());
+        }
+        Err(Error::UnsetVariable {
+            name: to_string(name),
+            position: Span::of(ctx.input, self.name),
+        })
+    }
+
+    /// Expands the reference, looking the variable up directly or through the
+    /// one it names.
+    fn expand_into<E, W>(
+        &self,
+        env: &mut E,
+        out: &mut W,
+        ctx: &Context,
+    ) -> Result<(), Error>
+    where
+        E: Enviroment,
+        W: Output,
+    {
+        if !self.indirect {
+            return self.expand_var_into(self.name, env, out, ctx);
+        }
+        let name = match self.lookup(self.name, env, ctx)? {
+            Some(name) => name,
+            None => {
+                self.unset(self.name, ctx)?;
+                OsString::new()
+            }
+        };
+        let name = os_to_bytes(&name, ctx.options.lossy)
+            .map_err(|_| self.not_unicode(self.name, ctx))?;
+        self.expand_var_into(&name, env, out, ctx)
+    }
+
+    /// Applies the operator to the variable `name`, rather than the name the
+    /// reference was parsed with.
+    fn expand_var_into<E, W>(
+        &self,
+        name: &[u8],
+        env: &mut E,
+        out: &mut W,
+        ctx: &Context,
+    ) -> Result<(), Error>
+    where
+        E: Enviroment,
+        W: Output,
+    {
+        let Some(operator) = &self.operator else {
+            return match self.lookup(name, env, ctx)? {
+                Some(val) => self.write_value(name, &val, out, ctx),
+                None => self.unset(name, ctx),
+            };
+        };
+        match operator {
+            Operator::Default(colon, default) => {
+                match check_null(self.lookup(name, env, ctx)?, *colon) {
+                    Some(val) => self.write_value(name, &val, out, ctx)?,
+                    None => ctx
+                        .nested(self.name, || expand_all_into(default, env, out, ctx))?,
+                }
+            }
+            Operator::Assign(colon, default) => {
+                match check_null(self.lookup(name, env, ctx)?, *colon) {
+                    Some(val) => self.write_value(name, &val, out, ctx)?,
+                    None => {
+                        let default =
+                            ctx.nested(self.name, || expand_all_os(default, env, ctx))?;
+                        if is_valid_name(name) && ctx.allows(name) {
+                            if default.as_encoded_bytes().contains(&0) {
+                                return Err(Error::NulInValue {
+                                    name: to_string(name),
+                                    position: Span::of(ctx.input, self.name),
+                                });
+                            }
+                            env.set(to_str(name)?, &default);
+                        }
+                        ctx.release(default.len());
+                        self.write_value(name, &default, out, ctx)?
+                    }
+                }
+            }
+            Operator::Alternate(colon, alternate) => {
+                if check_null(self.lookup(name, env, ctx)?, *colon).is_some() {
+                    ctx.nested(self.name, || expand_all_into(alternate, env, out, ctx))?
+                }
+            }
+            Operator::Error(colon, message) => {
+                match check_null(self.lookup(name, env, ctx)?, *colon) {
+                    Some(val) => self.write_value(name, &val, out, ctx)?,
+                    None => {
+                        let name = to_string(name);
+                        let mut message =
+                            ctx.nested(self.name, || expand_all(message, env, ctx))?;
+                        if message.is_empty() {
+                            message = "parameter null or not set".into();
+                        }
+                        return Err(Error::ParameterNullOrNotSet {
+                            name,
+                            message,
+                            position: Span::of(ctx.input, self.name),
+                        });
+                    }
+                }
+            }
+            Operator::Substring(offset, length) => {
+                let value = match self.lookup(name, env, ctx)? {
+                    Some(value) => value,
+                    None => {
+                        self.unset(name, ctx)?;
+                        OsString::new()
+                    }
+                };
+                let value = decode(&value, ctx.options.lossy)
+                    .map_err(|_| self.not_unicode(name, ctx))?;
+                out.write_str(substring(name, &value, offset, *length, ctx)?)?
             }
         }
+        Ok(())
     }
 }
@@ -0,0 +1,107 @@
+//! Finding out which variables a template refers to, without expanding it.
+
+use crate::{
+    error::Error,
+    token::{Operator, Token},
+};
+
+/// How much a template depends on a variable, ordered from least to most.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Requirement {
+    /// Only looked up depending on other variables, such as `BAR` in
+    /// `${FOO:-$BAR}`.
+    Conditional,
+    /// Always looked up, but with a fallback when unset, as in `${FOO:-default}`,
+    /// `${FOO:=default}` or `${FOO:+alternate}`.
+    Defaulted,
+    /// Always looked up with no fallback, as in `$FOO`, `${FOO:?}`,
+    /// `${FOO:1}` or `${!FOO:-default}`.
+    Required,
+}
+
+/// A variable that a template refers to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Variable<'a> {
+    pub name: &'a str,
+    pub requirement: Requirement,
+}
+
+/// The template the tokens were parsed from, and the variables found so far.
+struct Collector<'i, 'a> {
+    input: &'i [u8],
+    vars: Vec<Variable<'a>>,
+}
+
+fn add<'a>(
+    vars: &mut Collector<'_, 'a>,
+    name: &'a [u8],
+    requirement: Requirement,
+) -> Result<(), Error> {
+    let name =
+        std::str::from_utf8(name).map_err(|_| Error::not_unicode(vars.input, name))?;
+    let vars = &mut vars.vars;
+    match vars.iter_mut().find(|var| var.name == name) {
+        Some(var) => var.requirement = var.requirement.max(requirement),
+        None => vars.push(Variable { name, requirement }),
+    }
+    Ok(())
+}
+
+fn collect_token<'a>(
+    tok: &Token<'a>,
+    conditional: bool,
+    vars: &mut Collector<'_, 'a>,
+) -> Result<(), Error> {
+    let Token::Param(param) = tok else {
+        return Ok(());
+    };
+
+    let (requirement, word) = match &param.operator {
+        None | Some(Operator::Substring(..)) => (Requirement::Required, None),
+        Some(Operator::Error(_, message)) => (Requirement::Required, Some(message)),
+        Some(
+            Operator::Default(_, word)
+            | Operator::Assign(_, word)
+            | Operator::Alternate(_, word),
+        ) => (Requirement::Defaulted, Some(word)),
+    };
+    let requirement = if conditional {
+        Requirement::Conditional
+    } else if param.indirect {
+        // The operator applies to the variable pointed to, the pointer itself
+        // is always looked up.
+        Requirement::Required
+    } else {
+        requirement
+    };
+
+    add(vars, param.name, requirement)?;
+    match word {
+        Some(word) => collect(word, true, vars),
+        None => Ok(()),
+    }
+}
+
+fn collect<'a>(
+    tokens: &[Token<'a>],
+    conditional: bool,
+    vars: &mut Collector<'_, 'a>,
+) -> Result<(), Error> {
+    tokens
+        .iter()
+        .try_for_each(|tok| collect_token(tok, conditional, vars))
+}
+
+/// Lists each variable the tokens refer to once, in order of first appearance,
+/// with the strongest requirement of all its references.
+pub(crate) fn from_tokens<'a>(
+    input: &[u8],
+    tokens: &[Token<'a>],
+) -> Result<Vec<Variable<'a>>, Error> {
+    let mut vars = Collector {
+        input,
+        vars: Vec::new(),
+    };
+    collect(tokens, false, &mut vars)?;
+    Ok(vars.vars)
+}